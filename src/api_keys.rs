@@ -0,0 +1,134 @@
+//! Authenticates clients with the API keys configured in the `[authentication]` table, or in its key file.
+//!
+//! Keys are sent as bearer tokens, and only their SHA-256 hashes are kept, so that a leaked configuration
+//! doesn't leak the keys with it. Each key grants a set of scopes, which decide the routes it can be used on.
+//! The signed routes aren't covered, as their signatures already authorise them.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use axum::http::Method;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error as _;
+use tokio::fs;
+
+use crate::secrets;
+
+/// The scopes of access that API keys can grant.
+#[derive(Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Requesting upload URLs.
+    Upload,
+    /// Requesting download URLs and file information.
+    Download,
+    /// Deleting files with their deletion tokens.
+    Delete,
+    /// Everything, including deleting any file without its deletion token.
+    Admin,
+}
+
+impl Scope {
+    /// Returns the name of the scope, as used in the configuration.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Upload => "upload",
+            Self::Download => "download",
+            Self::Delete => "delete",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// Returns the scope that a request to the given route requires, if it requires one.
+    pub fn required_for(method: &Method, route: &str) -> Option<Self> {
+        match route {
+            "/upload" => Some(Self::Upload),
+            "/download/:uuid" | "/files/:uuid/info" => Some(Self::Download),
+            "/files/:uuid" if method == Method::DELETE => Some(Self::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// Returns whether the given scopes include the given one, which the admin scope always does.
+pub fn allows(scopes: &[Scope], scope: Scope) -> bool {
+    scopes.contains(&Scope::Admin) || scopes.contains(&scope)
+}
+
+/// An API key, of which only the hash is known.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    /// The name that the key is known by in logs.
+    name: String,
+    /// The SHA-256 hash of the key, encoded as lowercase hexadecimal.
+    #[serde(deserialize_with = "deserialize_hash")]
+    hash: String,
+    /// The scopes that the key grants.
+    scopes: Vec<Scope>,
+}
+
+impl ApiKey {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+}
+
+/// Deserializes a SHA-256 hash encoded as hexadecimal, in either case.
+fn deserialize_hash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let hash = String::deserialize(deserializer)?.to_ascii_lowercase();
+
+    if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(D::Error::custom("an API key hash must be a SHA-256 hash encoded as 64 hexadecimal digits"));
+    }
+
+    Ok(hash)
+}
+
+/// The API keys that clients can authenticate with, indexed by their hashes.
+#[derive(Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(from = "Vec<ApiKey>")]
+pub struct ApiKeys(HashMap<String, ApiKey>);
+
+impl From<Vec<ApiKey>> for ApiKeys {
+    fn from(keys: Vec<ApiKey>) -> Self {
+        let mut api_keys = Self::default();
+        api_keys.extend(keys);
+        api_keys
+    }
+}
+
+impl Extend<ApiKey> for ApiKeys {
+    fn extend<I: IntoIterator<Item = ApiKey>>(&mut self, keys: I) {
+        self.0.extend(keys.into_iter().map(|key| (key.hash.clone(), key)));
+    }
+}
+
+impl ApiKeys {
+    /// Returns the API key that the given bearer token is, if it is one.
+    pub fn find(&self, token: &str) -> Option<&ApiKey> {
+        self.0.get(&secrets::hash(token))
+    }
+}
+
+/// The contents of a key file, which lists keys in the same format as the `[authentication]` table.
+#[derive(Deserialize)]
+struct KeyFile {
+    /// The keys in the file.
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+/// Reads the API keys from a key file.
+pub async fn read_key_file(path: &Path) -> Result<Vec<ApiKey>, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path).await
+        .map_err(|e| format!("failed to read the key file {}: {e}", path.display()))?;
+
+    let key_file: KeyFile = toml::from_str(&text)
+        .map_err(|e| format!("failed to parse the key file {}: {e}", path.display()))?;
+
+    Ok(key_file.keys)
+}
@@ -0,0 +1,107 @@
+//! Contains the garbage collector, which deletes expired files and abandoned uploads from the active service.
+
+use core::time::Duration;
+
+use tokio::time::sleep;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{metadata, tus, AppState};
+
+/// How long an incomplete upload must have been idle, and its upload URL expired, before it is deleted.
+///
+/// This is generous, as uploads to external services can't be observed while they are running.
+const ABANDONED_UPLOAD_GRACE: Duration = Duration::from_hours(24);
+
+/// Deletes expired files and abandoned uploads every collection interval, for the lifetime of the program.
+///
+/// The interval is looked up anew each time, so that reloading the configuration changes it.
+pub async fn collect_garbage(state: &'static AppState) {
+    loop {
+        sleep(state.config().collection_interval()).await;
+
+        collect_expired(state).await;
+        collect_abandoned(state).await;
+    }
+}
+
+/// Deletes the files whose time-to-live has passed, or whose last download URL has expired.
+async fn collect_expired(state: &AppState) {
+    let expired = match state.metadata.expired().await {
+        Ok(expired) => expired,
+        Err(e) => {
+            error!("Failed to look up expired files: {e:?}");
+            return;
+        }
+    };
+
+    for uuid in expired {
+        if delete(state, uuid).await {
+            info!("Deleted expired file {uuid}");
+        }
+    }
+}
+
+/// Deletes the uploads that are still incomplete a while after their upload URLs have expired, along with
+/// whatever was staged of them.
+///
+/// Upload URLs are only checked when a transfer starts, so a transfer may still be running long after its URL
+/// expired. Uploads are therefore only collected once [`ABANDONED_UPLOAD_GRACE`] has passed since both their URL
+/// expired and they were last written to, and never while a request holds their lock.
+async fn collect_abandoned(state: &AppState) {
+    let cutoff = metadata::now().saturating_sub(ABANDONED_UPLOAD_GRACE.as_secs());
+
+    let incomplete = match state.metadata.incomplete(cutoff).await {
+        Ok(incomplete) => incomplete,
+        Err(e) => {
+            error!("Failed to look up incomplete uploads: {e:?}");
+            return;
+        }
+    };
+
+    for uuid in incomplete {
+        if tus::is_active(uuid) {
+            continue;
+        }
+
+        match state.service.last_upload_activity(uuid).await {
+            Ok(Some(last_activity)) if last_activity >= cutoff => continue,
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to look up the activity of upload {uuid}: {e:?}");
+                continue;
+            }
+        }
+
+        // uploads to external services bypass Pithos, so their completion may not have been noticed yet
+        match state.service.has_object(uuid).await {
+            Ok(true) => {
+                if let Err(e) = state.metadata.record_completed(uuid).await {
+                    error!("Failed to record completion of upload {uuid}: {e:?}");
+                }
+            }
+            Ok(false) => {
+                if delete(state, uuid).await {
+                    info!("Deleted abandoned upload {uuid}");
+                }
+            }
+            Err(e) => error!("Failed to look up upload {uuid}: {e:?}"),
+        }
+    }
+}
+
+/// Deletes a file from the service and records its deletion, returning whether both succeeded.
+async fn delete(state: &AppState, uuid: Uuid) -> bool {
+    if let Err(e) = state.service.delete(uuid).await {
+        error!("Failed to delete file {uuid}: {e:?}");
+        return false;
+    }
+
+    if let Err(e) = state.metadata.record_deleted(uuid).await {
+        error!("Failed to record deletion of file {uuid}: {e:?}");
+        return false;
+    }
+
+    state.coverage.forget(uuid);
+    true
+}
@@ -1,9 +1,15 @@
 //! A module for managing the configuration of Pithos.
 
-use std::net::IpAddr;
-use std::path::PathBuf;
+use core::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use axum_client_ip::SecureClientIpSource;
-use serde::Deserialize;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use serde_with::{serde_as, DurationSeconds};
+use tracing::instrument;
+use crate::api_keys::{ApiKey, ApiKeys, Scope};
+use crate::ip_filter::IpSet;
 use crate::service::AvailableService;
 
 /// A parsed representation of the configuration file.
@@ -11,6 +17,9 @@ use crate::service::AvailableService;
 pub struct Config {
     /// The path for files when using Pithos as a storage provider.
     local_storage_path: PathBuf,
+    /// The path of the database recording the metadata of stored files.
+    #[serde(default = "default_metadata_path")]
+    metadata_path: PathBuf,
     /// The access management service to use.
     service: AvailableService,
     /// The configuration for services
@@ -19,8 +28,16 @@ pub struct Config {
     files: Files,
     /// The table containing the IP address blacklist.
     ip_blacklist: IpBlacklist,
+    /// The table containing the IP address allowlist, which admits everyone if it is missing.
+    #[serde(default)]
+    ip_allowlist: IpAllowlist,
     /// The table containing the server configuration
     server: Server,
+    /// The table containing the rate limits, which are disabled if it is missing.
+    #[serde(default)]
+    rate_limits: RateLimits,
+    /// The table configuring API keys, which admits anonymous clients to every route if it is missing.
+    authentication: Option<Authentication>,
 }
 
 impl Config {
@@ -29,10 +46,57 @@ impl Config {
         self.files.max_upload_size
     }
 
+    /// Returns the longest time-to-live that clients may request for their uploads, if it is limited.
+    pub(crate) const fn max_ttl(&self) -> Option<Duration> {
+        self.files.max_ttl
+    }
+
+    /// Returns the time-to-live of uploads that don't request one, if they are given one.
+    pub(crate) const fn default_ttl(&self) -> Option<Duration> {
+        self.files.default_ttl
+    }
+
+    /// Returns the free space, in bytes, below which the local storage is reported as not ready.
+    pub(crate) const fn min_free_space(&self) -> u64 {
+        self.files.min_free_space
+    }
+
+    /// Returns how often expired files are deleted.
+    pub(crate) const fn collection_interval(&self) -> Duration {
+        self.files.collection_interval
+    }
+
+    /// Returns how long the URLs issued by services remain valid.
+    pub(crate) const fn url_lifetimes(&self) -> UrlLifetimes {
+        UrlLifetimes {
+            upload: self.files.upload_url_lifetime,
+            resumable_upload: self.files.resumable_upload_url_lifetime,
+            download: self.files.download_url_lifetime,
+        }
+    }
+
+    /// Returns the limits on the byte ranges that may be requested in a single download.
+    pub(crate) const fn range_limits(&self) -> RangeLimits {
+        RangeLimits {
+            max_ranges: self.files.max_ranges,
+            max_overlapping_ranges: self.files.max_overlapping_ranges,
+        }
+    }
+
     pub(crate) fn local_storage_path(&self) -> PathBuf {
         self.local_storage_path.clone()
     }
 
+    /// Returns the path of the metadata database.
+    pub(crate) fn metadata_path(&self) -> PathBuf {
+        self.metadata_path.clone()
+    }
+
+    /// Returns the path for files that are still being uploaded to the local storage.
+    pub(crate) fn staging_path(&self) -> PathBuf {
+        self.local_storage_path.join(".staging")
+    }
+
     pub(crate) const fn chosen_service(&self) -> AvailableService {
         self.service
     }
@@ -41,21 +105,95 @@ impl Config {
         self.services.google_cloud_storage.clone()
     }
 
+    /// Returns the configuration for the S3-compatible storage service, if it is present.
+    pub(crate) fn s3_config(&self) -> Option<S3Options> {
+        self.services.s3.clone()
+    }
+
     /// Returns whether the given IP address is blocked.
+    ///
+    /// The blacklist takes precedence: an address in a blocked range is blocked even if it is also in an allowed range.
+    /// Otherwise, if the allowlist has any ranges, only the addresses in them are admitted.
+    #[instrument(skip(self))]
     pub(crate) fn is_blocked(&self, ip: &IpAddr) -> bool {
-        self.ip_blacklist.blocked_ips.contains(ip)
+        let allowed_ips = &self.ip_allowlist.allowed_ips;
+        self.ip_blacklist.blocked_ips.contains(ip) || (!allowed_ips.is_empty() && !allowed_ips.contains(ip))
+    }
+
+    /// Returns the API key configuration, if clients must authenticate for any routes.
+    pub(crate) const fn authentication(&self) -> Option<&Authentication> {
+        self.authentication.as_ref()
+    }
+
+    /// Returns the path of the file with more API keys, if there is one.
+    pub(crate) fn api_key_file(&self) -> Option<PathBuf> {
+        self.authentication.as_ref().and_then(|authentication| authentication.key_file.clone())
+    }
+
+    /// Adds the given keys to the API keys from the configuration, e.g. those from the key file.
+    pub(crate) fn add_api_keys(&mut self, keys: Vec<ApiKey>) {
+        if let Some(authentication) = &mut self.authentication {
+            authentication.keys.extend(keys);
+        }
+    }
+
+    /// Returns the limits on how often clients may request upload and download URLs.
+    pub(crate) const fn request_rate_limit(&self) -> RateLimit {
+        self.rate_limits.requests
+    }
+
+    /// Returns the limits on how many bytes per second clients may transfer on the signed routes.
+    pub(crate) const fn byte_rate_limit(&self) -> RateLimit {
+        self.rate_limits.bytes
     }
 
     /// Returns the client IP source.
     pub(crate) fn get_ip_source(&self) -> SecureClientIpSource {
         self.server.ip_source.clone()
     }
+
+    /// Returns how long in-flight requests may take to finish once Pithos is asked to shut down.
+    pub(crate) const fn shutdown_grace_period(&self) -> Duration {
+        self.server.shutdown_grace_period
+    }
+
+    /// Returns the addresses to listen on, if they are configured.
+    pub(crate) fn listen_options(&self) -> Option<ListenOptions> {
+        self.server.listen.clone()
+    }
+
+    /// Returns the address to serve Prometheus metrics on, if they should be served.
+    pub(crate) const fn metrics_address(&self) -> Option<SocketAddr> {
+        self.server.metrics_address
+    }
+
+    /// Returns the TLS configuration, if Pithos should terminate TLS itself.
+    pub(crate) fn tls_options(&self) -> Option<TlsOptions> {
+        self.server.tls.clone()
+    }
+
+    /// Returns the format that logs are written in.
+    pub(crate) const fn log_format(&self) -> LogFormat {
+        self.server.log_format
+    }
+
+    /// Returns the trace export configuration, if traces should be exported.
+    pub(crate) fn tracing_options(&self) -> Option<TracingOptions> {
+        self.server.tracing.clone()
+    }
+}
+
+/// The metadata database is kept in the working directory by default.
+fn default_metadata_path() -> PathBuf {
+    PathBuf::from("metadata.sqlite3")
 }
 
 #[derive(Deserialize)]
 struct Services {
     /// Configuration for the Google Cloud Storage service
-    google_cloud_storage: GoogleCloudStorageOptions
+    google_cloud_storage: GoogleCloudStorageOptions,
+    /// Configuration for the S3-compatible storage service
+    s3: Option<S3Options>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -71,23 +209,365 @@ impl GoogleCloudStorageOptions {
     }
 }
 
+#[derive(Deserialize, Clone)]
+pub struct S3Options {
+    /// The name of the bucket to use.
+    bucket: String,
+    /// The region of the bucket. S3-compatible stores usually accept any value here.
+    region: String,
+    /// The endpoint of the S3-compatible store, e.g. `http://localhost:9000` for a local `MinIO` instance.
+    endpoint: String,
+    /// Whether to address the bucket as part of the path instead of as a subdomain of the endpoint.
+    #[serde(default = "default_path_style")]
+    path_style: bool,
+}
+
+impl S3Options {
+    pub(crate) fn bucket_name(&self) -> String {
+        self.bucket.clone()
+    }
+
+    pub(crate) fn region(&self) -> String {
+        self.region.clone()
+    }
+
+    pub(crate) fn endpoint(&self) -> String {
+        self.endpoint.clone()
+    }
+
+    pub(crate) const fn path_style(&self) -> bool {
+        self.path_style
+    }
+}
+
+/// Most self-hosted S3-compatible stores only support path-style addressing.
+const fn default_path_style() -> bool {
+    true
+}
+
 /// The table containing configuration for file uploads.
+#[serde_as]
 #[derive(Deserialize)]
 struct Files {
     /// The maximum size of individual uploads in bytes.
     max_upload_size: u64,
+    /// How long upload URLs remain valid after being issued.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_url_lifetime")]
+    upload_url_lifetime: Duration,
+    /// How long resumable upload URLs remain valid after being issued, during which interrupted uploads can be resumed.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_resumable_url_lifetime")]
+    resumable_upload_url_lifetime: Duration,
+    /// How long download URLs remain valid after being issued.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_url_lifetime")]
+    download_url_lifetime: Duration,
+    /// The longest time-to-live that clients may request.
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default)]
+    max_ttl: Option<Duration>,
+    /// The time-to-live of uploads that don't request one, which are kept forever if this is missing.
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default)]
+    default_ttl: Option<Duration>,
+    /// How often expired files are deleted.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_collection_interval")]
+    collection_interval: Duration,
+    /// The free space, in bytes, below which the local storage is reported as not ready.
+    #[serde(default = "default_min_free_space")]
+    min_free_space: u64,
+    /// The largest number of byte ranges that may be requested in a single download.
+    #[serde(default = "default_max_ranges")]
+    max_ranges: usize,
+    /// The largest number of requested byte ranges that may contain the same byte.
+    #[serde(default = "default_max_overlapping_ranges")]
+    max_overlapping_ranges: usize,
+}
+
+/// A gibibyte leaves room for a few uploads, without requiring a large disk.
+const fn default_min_free_space() -> u64 {
+    1024 * 1024 * 1024
+}
+
+/// Media players rarely ask for more than a handful of ranges at once.
+const fn default_max_ranges() -> usize {
+    16
+}
+
+/// More than two overlapping ranges are, per RFC 9110, an indication of a broken client or a denial-of-service attack.
+const fn default_max_overlapping_ranges() -> usize {
+    2
+}
+
+/// Expired files are deleted every minute by default.
+const fn default_collection_interval() -> Duration {
+    Duration::from_mins(1)
+}
+
+/// URLs remain valid for half an hour by default.
+const fn default_url_lifetime() -> Duration {
+    Duration::from_mins(30)
+}
+
+/// Resumable upload URLs remain valid for a week by default, so that large uploads can be resumed after
+/// a long interruption.
+const fn default_resumable_url_lifetime() -> Duration {
+    Duration::from_hours(7 * 24)
+}
+
+/// How long the URLs issued by services remain valid.
+#[derive(Copy, Clone)]
+pub struct UrlLifetimes {
+    /// The lifetime of upload URLs.
+    pub upload: Duration,
+    /// The lifetime of resumable upload URLs.
+    pub resumable_upload: Duration,
+    /// The lifetime of download URLs.
+    pub download: Duration,
+}
+
+/// The limits on the byte ranges that may be requested in a single download.
+#[derive(Copy, Clone)]
+pub struct RangeLimits {
+    /// The largest number of ranges.
+    pub max_ranges: usize,
+    /// The largest number of ranges that may contain the same byte.
+    pub max_overlapping_ranges: usize,
 }
 
 /// The table containing the IP address blacklist.
 #[derive(Deserialize)]
 struct IpBlacklist {
-    /// The list of IP addresses that are blocked from using Pithos.
-    blocked_ips: Vec<IpAddr>,
+    /// The IP addresses and CIDR ranges that are blocked from using Pithos.
+    blocked_ips: IpSet,
+}
+
+/// The table containing the IP address allowlist.
+#[derive(Deserialize, Default)]
+struct IpAllowlist {
+    /// The IP addresses and CIDR ranges that may use Pithos, or an empty list to admit everyone.
+    #[serde(default)]
+    allowed_ips: IpSet,
+}
+
+/// The table configuring API keys.
+#[derive(Deserialize, PartialEq, Eq)]
+pub struct Authentication {
+    /// The scopes that clients without an API key have, which must be given even if they have none.
+    anonymous_scopes: Vec<Scope>,
+    /// The keys that clients can authenticate with.
+    #[serde(default)]
+    keys: ApiKeys,
+    /// The path of a file with more keys, if there is one.
+    key_file: Option<PathBuf>,
+}
+
+impl Authentication {
+    pub(crate) fn anonymous_scopes(&self) -> &[Scope] {
+        &self.anonymous_scopes
+    }
+
+    pub(crate) const fn keys(&self) -> &ApiKeys {
+        &self.keys
+    }
 }
 
 /// The table containing the server configuration
+#[serde_as]
 #[derive(Deserialize)]
 struct Server {
     /// The source for obtaining the client's IP address
     ip_source: SecureClientIpSource,
-}
\ No newline at end of file
+    /// How long in-flight requests may take to finish once Pithos is asked to shut down.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_shutdown_grace_period")]
+    shutdown_grace_period: Duration,
+    /// The table containing the addresses to listen on, which is missing if the `PORT` environment variable decides.
+    listen: Option<ListenOptions>,
+    /// The table containing the TLS configuration, which is missing if Pithos serves plaintext HTTP.
+    tls: Option<TlsOptions>,
+    /// The address to serve Prometheus metrics on, which is missing if they shouldn't be served.
+    metrics_address: Option<SocketAddr>,
+    /// The format that logs are written to standard output in.
+    #[serde(default)]
+    log_format: LogFormat,
+    /// The table configuring trace export, which is missing if traces aren't exported.
+    tracing: Option<TracingOptions>,
+}
+
+/// The formats that logs can be written in.
+#[derive(Deserialize, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines of text.
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+/// Uploads and downloads get half a minute to finish by default, which is also what most process managers wait for.
+const fn default_shutdown_grace_period() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// The table configuring the export of traces to an OpenTelemetry collector.
+#[derive(Deserialize, Clone)]
+pub struct TracingOptions {
+    /// The URL of the collector's OTLP endpoint.
+    otlp_endpoint: String,
+    /// The protocol that the collector receives traces over.
+    #[serde(default)]
+    protocol: OtlpProtocol,
+    /// The service name that traces are reported under.
+    #[serde(default = "default_service_name")]
+    service_name: String,
+}
+
+/// Traces are reported under the name of the program by default.
+fn default_service_name() -> String {
+    "pithos".to_string()
+}
+
+impl TracingOptions {
+    pub(crate) fn otlp_endpoint(&self) -> &str {
+        &self.otlp_endpoint
+    }
+
+    pub(crate) const fn protocol(&self) -> OtlpProtocol {
+        self.protocol
+    }
+
+    pub(crate) fn service_name(&self) -> &str {
+        &self.service_name
+    }
+}
+
+/// The protocols that traces can be exported over.
+#[derive(Deserialize, Copy, Clone, Default)]
+pub enum OtlpProtocol {
+    /// OTLP over gRPC, which collectors usually receive on port 4317.
+    #[default]
+    #[serde(rename = "grpc")]
+    Grpc,
+    /// OTLP as Protobuf over HTTP, which collectors usually receive on port 4318.
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+}
+
+/// The table containing the addresses to listen on.
+#[derive(Deserialize, Clone)]
+pub struct ListenOptions {
+    /// The TCP addresses to listen on, e.g. `0.0.0.0:8080` or `[::]:8080`.
+    #[serde(default)]
+    addresses: Vec<SocketAddr>,
+    /// The path of a Unix domain socket to listen on, if there should be one.
+    unix_socket: Option<PathBuf>,
+}
+
+impl ListenOptions {
+    /// Creates options for listening on a single TCP address.
+    pub(crate) fn tcp(addr: SocketAddr) -> Self {
+        Self { addresses: vec![addr], unix_socket: None }
+    }
+
+    pub(crate) fn addresses(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    pub(crate) fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    /// Returns whether there is nothing to listen on.
+    pub(crate) const fn is_empty(&self) -> bool {
+        self.addresses.is_empty() && self.unix_socket.is_none()
+    }
+}
+
+/// The table containing the TLS configuration.
+#[derive(Deserialize, Clone)]
+pub struct TlsOptions {
+    /// The path of the PEM file containing the certificate chain.
+    cert_path: PathBuf,
+    /// The path of the PEM file containing the private key.
+    key_path: PathBuf,
+    /// The path of the PEM file containing the certificate authorities that client certificates must be issued by.
+    /// Client certificates are only required if this is present.
+    client_ca_path: Option<PathBuf>,
+    /// The port of a plaintext HTTP listener that redirects every request to HTTPS, if there should be one.
+    redirect_port: Option<u16>,
+}
+
+impl TlsOptions {
+    pub(crate) fn cert_path(&self) -> &Path {
+        &self.cert_path
+    }
+
+    pub(crate) fn key_path(&self) -> &Path {
+        &self.key_path
+    }
+
+    pub(crate) fn client_ca_path(&self) -> Option<&Path> {
+        self.client_ca_path.as_deref()
+    }
+
+    pub(crate) const fn redirect_port(&self) -> Option<u16> {
+        self.redirect_port
+    }
+}
+
+/// The table containing the rate limits.
+#[derive(Deserialize, Default)]
+struct RateLimits {
+    /// The limits on requests for upload and download URLs, in requests.
+    #[serde(default)]
+    requests: RateLimit,
+    /// The limits on transfers on the signed routes, in bytes.
+    #[serde(default)]
+    bytes: RateLimit,
+}
+
+/// The limits of a rate limiter, each of which is disabled if it is missing.
+#[derive(Deserialize, Default, Copy, Clone)]
+pub struct RateLimit {
+    /// The limit for each client IP address.
+    pub per_ip: Option<BucketOptions>,
+    /// The limit shared by all clients.
+    pub global: Option<BucketOptions>,
+}
+
+/// The size and refill rate of a token bucket.
+#[derive(Deserialize, Copy, Clone)]
+pub struct BucketOptions {
+    /// How many tokens are added to the bucket each second, which must be positive.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub rate: f64,
+    /// How many tokens the bucket holds at most, which is how many can be used in a burst, and at least 1.
+    #[serde(deserialize_with = "deserialize_burst")]
+    pub burst: f64,
+}
+
+/// Deserializes the refill rate of a token bucket, which must be positive for the bucket to ever refill.
+fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let rate = f64::deserialize(deserializer)?;
+
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(D::Error::custom("the rate of a rate limit must be a positive number"));
+    }
+
+    Ok(rate)
+}
+
+/// Deserializes the capacity of a token bucket, which must hold at least one token for a request to ever pass.
+fn deserialize_burst<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let burst = f64::deserialize(deserializer)?;
+
+    if !(burst.is_finite() && burst >= 1.0) {
+        return Err(D::Error::custom("the burst of a rate limit must be at least 1"));
+    }
+
+    Ok(burst)
+}
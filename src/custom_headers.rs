@@ -1,45 +1,89 @@
+//! Contains the non-standard headers used by Pithos.
+
 use axum::headers::{self, Header, HeaderName, HeaderValue};
-use lazy_static::lazy_static;
 
-pub const X_FILE_SIZE: XFileNameHeaderName = XFileNameHeaderName {};
+/// Defines a header whose value is a single unsigned integer.
+macro_rules! integer_header {
+    ($(#[$meta:meta])* $header:ident, $name:ident, $text:literal) => {
+        pub const $name: HeaderName = HeaderName::from_static($text);
 
-lazy_static! {
-    static ref INTERNAL_TEXT: &'static [u8] = "x-file-size".as_bytes();
-    static ref INTERNAL_NAME: HeaderName = HeaderName::from_lowercase(&INTERNAL_TEXT).unwrap();
-}
+        $(#[$meta])*
+        pub struct $header(pub u64);
+
+        impl Header for $header {
+            fn name() -> &'static HeaderName {
+                static NAME: HeaderName = $name;
+                &NAME
+            }
 
-pub struct XFileNameHeaderName;
+            fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
+            where
+                I: Iterator<Item = &'i HeaderValue>,
+            {
+                let value = values
+                    .next()
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .ok_or_else(headers::Error::invalid)?;
+                Ok(Self(value))
+            }
 
-impl From<XFileNameHeaderName> for HeaderName {
-    fn from(_: XFileNameHeaderName) -> Self {
-        INTERNAL_NAME.clone()
-    }
+            fn encode<E>(&self, values: &mut E)
+            where
+                E: Extend<HeaderValue>,
+            {
+                values.extend(std::iter::once(HeaderValue::from(self.0)));
+            }
+        }
+    };
 }
 
-pub struct XFileSize(pub u64);
-
-impl Header for XFileSize {
-    fn name() -> &'static HeaderName {
-        &INTERNAL_NAME
-    }
-
-    fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
-    where
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        let value = values
-            .next()
-            .and_then(|value| value.to_str().ok())
-            .and_then(|value| value.parse::<u64>().ok())
-            .ok_or_else(headers::Error::invalid)?;
-        Ok(Self(value))
-    }
-
-    fn encode<E>(&self, values: &mut E)
-    where
-        E: Extend<HeaderValue>,
-    {
-        let value = HeaderValue::from_str(&self.0.to_string()).unwrap();
-        values.extend(std::iter::once(value));
-    }
-}
\ No newline at end of file
+integer_header!(
+    /// The size of the file that the client wants to upload, in bytes.
+    XFileSize, X_FILE_SIZE, "x-file-size"
+);
+
+integer_header!(
+    /// The number of seconds after which the client wants its upload to be deleted.
+    XFileTtl, X_FILE_TTL, "x-file-ttl"
+);
+
+integer_header!(
+    /// The number of times the client wants its upload to be downloadable before it is deleted.
+    XMaxDownloads, X_MAX_DOWNLOADS, "x-max-downloads"
+);
+
+integer_header!(
+    /// The total size of a resumable upload, in bytes.
+    UploadLength, UPLOAD_LENGTH, "upload-length"
+);
+
+integer_header!(
+    /// The number of bytes of a resumable upload that the server has received.
+    UploadOffset, UPLOAD_OFFSET, "upload-offset"
+);
+
+/// The digest that a client declares for the file it wants to upload.
+pub const X_FILE_DIGEST: HeaderName = HeaderName::from_static("x-file-digest");
+
+/// The digest of a downloaded file, as per RFC 9530.
+pub const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+/// The digest of a downloaded file, as per the obsolete RFC 3230.
+pub const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+/// The ID that a request is logged under, which clients may choose themselves.
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The secret with which the uploader of a file can delete it.
+pub const X_DELETION_TOKEN: HeaderName = HeaderName::from_static("x-deletion-token");
+
+/// The tus protocol version used by the client or server.
+pub const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+/// The tus protocol versions supported by the server.
+pub const TUS_VERSION: HeaderName = HeaderName::from_static("tus-version");
+/// The tus protocol extensions supported by the server.
+pub const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+/// The maximum size of a resumable upload supported by the server, in bytes.
+pub const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+/// The client-provided metadata of a resumable upload, which Pithos ignores.
+pub const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
@@ -0,0 +1,188 @@
+//! Computes and verifies the digests with which clients protect the integrity of their uploads.
+//!
+//! Digests are written in the format of the `Repr-Digest` header from RFC 9530, e.g. `sha-256=:<base64>:`.
+
+use core::fmt::{self, Display, Formatter};
+use core::str::FromStr;
+use std::io;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// The algorithms that uploads can be checked with.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+    /// SHA-256, as used by most clients.
+    Sha256,
+    /// CRC32C, as used by Google Cloud Storage.
+    Crc32c,
+}
+
+impl Algorithm {
+    /// Returns the name of the algorithm in the digest algorithm registry.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha-256",
+            Self::Crc32c => "crc32c",
+        }
+    }
+}
+
+/// The digest of the exact bytes of a file.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ContentDigest {
+    /// The algorithm that computed the digest.
+    pub algorithm: Algorithm,
+    /// The digest itself.
+    pub value: Vec<u8>,
+}
+
+/// The error returned when a digest is not in the expected format.
+#[derive(Debug)]
+pub struct MalformedDigest;
+
+impl Display for MalformedDigest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "digests must be given as sha-256=:<base64>: or crc32c=:<base64>:")
+    }
+}
+
+impl std::error::Error for MalformedDigest {}
+
+impl ContentDigest {
+    /// Returns the digest encoded as base64, as used by the headers of Google Cloud Storage and S3.
+    pub fn base64(&self) -> String {
+        STANDARD.encode(&self.value)
+    }
+
+    /// Returns the digest encoded as hexadecimal.
+    pub fn hex(&self) -> String {
+        hex::encode(&self.value)
+    }
+
+    /// Returns the digest in the format of the legacy `Digest` header from RFC 3230.
+    pub fn legacy(&self) -> String {
+        format!("{}={}", self.algorithm.name(), self.base64())
+    }
+}
+
+impl FromStr for ContentDigest {
+    type Err = MalformedDigest;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.trim().split_once('=').ok_or(MalformedDigest)?;
+        let algorithm = match name.to_ascii_lowercase().as_str() {
+            "sha-256" => Algorithm::Sha256,
+            "crc32c" => Algorithm::Crc32c,
+            _ => return Err(MalformedDigest),
+        };
+
+        let value = value.strip_prefix(':').and_then(|value| value.strip_suffix(':')).ok_or(MalformedDigest)?;
+        let value = STANDARD.decode(value).map_err(|_| MalformedDigest)?;
+
+        let expected_length = match algorithm {
+            Algorithm::Sha256 => 32,
+            Algorithm::Crc32c => 4,
+        };
+
+        if value.len() != expected_length {
+            return Err(MalformedDigest);
+        }
+
+        Ok(Self { algorithm, value })
+    }
+}
+
+impl Display for ContentDigest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=:{}:", self.algorithm.name(), self.base64())
+    }
+}
+
+/// The digest of a local upload, as carried in the signed query parameters of its upload URL.
+///
+/// Digests are carried as hexadecimal, so that they need no escaping in URLs.
+#[derive(Deserialize)]
+pub struct DigestQuery {
+    /// The expected SHA-256 digest of the upload, if any.
+    sha256: Option<String>,
+    /// The expected CRC32C digest of the upload, if any.
+    crc32c: Option<String>,
+}
+
+impl DigestQuery {
+    /// Returns the query parameter that carries the given digest.
+    pub fn parameter(digest: &ContentDigest) -> (&'static str, String) {
+        let name = match digest.algorithm {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Crc32c => "crc32c",
+        };
+
+        (name, digest.hex())
+    }
+
+    /// Returns the digest carried in the query parameters, if there is one.
+    pub fn digest(&self) -> Option<ContentDigest> {
+        let (algorithm, value) = match (&self.sha256, &self.crc32c) {
+            (Some(value), _) => (Algorithm::Sha256, value),
+            (None, Some(value)) => (Algorithm::Crc32c, value),
+            (None, None) => return None,
+        };
+
+        hex::decode(value).ok().map(|value| ContentDigest { algorithm, value })
+    }
+}
+
+/// Computes the digest of a file as it is being streamed.
+pub enum Hasher {
+    /// Computes a SHA-256 digest.
+    Sha256(Sha256),
+    /// Computes a CRC32C digest.
+    Crc32c(u32),
+}
+
+impl Hasher {
+    /// Creates a hasher for the given algorithm.
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Self::Sha256(Sha256::new()),
+            Algorithm::Crc32c => Self::Crc32c(0),
+        }
+    }
+
+    /// Feeds the next bytes of the file to the hasher.
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(bytes),
+            Self::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, bytes),
+        }
+    }
+
+    /// Returns the digest of all the bytes fed to the hasher.
+    pub fn finish(self) -> ContentDigest {
+        match self {
+            Self::Sha256(hasher) => ContentDigest { algorithm: Algorithm::Sha256, value: hasher.finalize().to_vec() },
+            // CRC32C digests are big-endian, as in Google Cloud Storage
+            Self::Crc32c(crc) => ContentDigest { algorithm: Algorithm::Crc32c, value: crc.to_be_bytes().to_vec() },
+        }
+    }
+}
+
+/// Computes the digest of a file on disk with the given algorithm.
+pub async fn of_file(path: &Path, algorithm: Algorithm) -> io::Result<ContentDigest> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        match file.read(&mut buffer).await? {
+            0 => return Ok(hasher.finish()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
@@ -2,13 +2,23 @@
 
 use std::error::Error;
 use core::fmt::{self, Debug, Display, Formatter};
+use core::time::Duration;
 use axum::{http, Json};
+use axum::headers::{ContentRange, HeaderMapExt};
+use axum::http::HeaderValue;
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
 use axum::response::{IntoResponse, Response};
 use axum::extract::rejection::QueryRejection;
 use google_cloud_storage::sign::SignedURLError;
 use http::status::StatusCode;
+use s3::error::S3Error;
 
+use crate::api_keys::Scope;
+use crate::custom_headers::TUS_VERSION;
+use crate::digests::Algorithm;
 use crate::file_extensions::ExtensionError;
+use crate::logging::RequestId;
+use crate::tus::TUS_SUPPORTED_VERSION;
 
 use serde_json::json;
 use tracing::error;
@@ -17,35 +27,115 @@ use tracing::error;
 #[derive(Debug)]
 pub enum PithosError {
     /// An error occurred while trying to create a signed URL.
-    Access(Box<dyn Error>),
+    Access(Box<dyn Error + Send + Sync>),
     /// The file that the user wants to upload is larger than the configured maximum upload size.
     TooLarge(u64, u64),
     /// The user is blocked from using this service, i.e. their IP is on the blacklist.
     Blocked,
     /// The user requested a byte range that is outside the file's current data.
     InvalidRange(u64, u64, u64),
+    /// The user requested more byte ranges, or more heavily overlapping ones, than allowed.
+    ExcessiveRanges,
     /// The request succeeded, but an internal error occurred when attempting to write the file.
-    ServerError(Box<dyn Error>),
+    ServerError(Box<dyn Error + Send + Sync>),
     /// The local file being requested doesn't exist.
     NoSuchFile,
     /// The requested query parameters were invalid
-    InvalidQuery(Box<dyn Error>),
+    InvalidQuery(Box<dyn Error + Send + Sync>),
+    /// The active service doesn't support resumable uploads.
+    ResumableUnsupported,
+    /// The client doesn't speak a version of the tus protocol that Pithos supports.
+    UnsupportedTusVersion,
+    /// The client tried to resume an upload from a different offset than the server has, as (given, actual).
+    OffsetMismatch(u64, u64),
+    /// The client sent an upload chunk with a content type other than `application/offset+octet-stream`.
+    UnsupportedMediaType,
+    /// The client sent a different number of bytes than it declared, as (declared, sent).
+    SizeMismatch(u64, u64),
+    /// The client sent more bytes than it declared.
+    ExceedsDeclaredSize(u64),
+    /// The file being uploaded already exists.
+    AlreadyExists,
+    /// Another request is currently writing to the same upload.
+    Locked,
+    /// The signed URL being used expired at the given UNIX timestamp.
+    ExpiredUrl(u64),
+    /// The file being requested has expired or been deleted.
+    Gone,
+    /// The deletion token given for a file was missing or incorrect.
+    InvalidDeletionToken,
+    /// The digest declared for an upload is malformed.
+    InvalidDigest(Box<dyn Error + Send + Sync>),
+    /// The active service can't verify uploads with the given digest algorithm.
+    UnsupportedDigest(Algorithm),
+    /// The uploaded bytes don't match the digest declared for them.
+    DigestMismatch,
+    /// The client has exceeded its rate limit, and may try again after the given time.
+    RateLimited(Duration),
+    /// The client sent an unknown API key, or none where one is required.
+    Unauthenticated,
+    /// The client's API key doesn't grant the scope that the request requires.
+    MissingScope(Scope),
 }
 
 impl PithosError {
     /// Returns the HTTP status code for this error.
     pub const fn status_code(&self) -> StatusCode {
         match self {
-            Self::TooLarge(_, _) => StatusCode::PAYLOAD_TOO_LARGE,
-            Self::Blocked => StatusCode::FORBIDDEN,
+            Self::TooLarge(_, _) | Self::ExceedsDeclaredSize(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Blocked | Self::ExpiredUrl(_) | Self::InvalidDeletionToken | Self::MissingScope(_) => StatusCode::FORBIDDEN,
             Self::Access(_) | Self::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::NoSuchFile => StatusCode::NOT_FOUND,
-            Self::InvalidRange(_, _, _) => StatusCode::RANGE_NOT_SATISFIABLE,
-            Self::InvalidQuery(_) => StatusCode::BAD_REQUEST
+            Self::InvalidRange(_, _, _) | Self::ExcessiveRanges => StatusCode::RANGE_NOT_SATISFIABLE,
+            Self::InvalidQuery(_) | Self::SizeMismatch(_, _) | Self::InvalidDigest(_) | Self::UnsupportedDigest(_)
+                | Self::DigestMismatch => StatusCode::BAD_REQUEST,
+            Self::ResumableUnsupported => StatusCode::NOT_IMPLEMENTED,
+            Self::UnsupportedTusVersion => StatusCode::PRECONDITION_FAILED,
+            Self::OffsetMismatch(_, _) | Self::AlreadyExists => StatusCode::CONFLICT,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Locked => StatusCode::LOCKED,
+            Self::Gone => StatusCode::GONE,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unauthenticated => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// Returns the name of this error's variant, as used in metrics.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Access(_) => "Access",
+            Self::TooLarge(_, _) => "TooLarge",
+            Self::Blocked => "Blocked",
+            Self::InvalidRange(_, _, _) => "InvalidRange",
+            Self::ExcessiveRanges => "ExcessiveRanges",
+            Self::ServerError(_) => "ServerError",
+            Self::NoSuchFile => "NoSuchFile",
+            Self::InvalidQuery(_) => "InvalidQuery",
+            Self::ResumableUnsupported => "ResumableUnsupported",
+            Self::UnsupportedTusVersion => "UnsupportedTusVersion",
+            Self::OffsetMismatch(_, _) => "OffsetMismatch",
+            Self::UnsupportedMediaType => "UnsupportedMediaType",
+            Self::SizeMismatch(_, _) => "SizeMismatch",
+            Self::ExceedsDeclaredSize(_) => "ExceedsDeclaredSize",
+            Self::AlreadyExists => "AlreadyExists",
+            Self::Locked => "Locked",
+            Self::ExpiredUrl(_) => "ExpiredUrl",
+            Self::Gone => "Gone",
+            Self::InvalidDeletionToken => "InvalidDeletionToken",
+            Self::InvalidDigest(_) => "InvalidDigest",
+            Self::UnsupportedDigest(_) => "UnsupportedDigest",
+            Self::DigestMismatch => "DigestMismatch",
+            Self::RateLimited(_) => "RateLimited",
+            Self::Unauthenticated => "Unauthenticated",
+            Self::MissingScope(_) => "MissingScope",
         }
     }
 }
 
+/// The name of the error that a response was made from, which is kept in the response's extensions for the metrics.
+#[derive(Copy, Clone)]
+pub struct ErrorName(pub &'static str);
+
 impl Display for PithosError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -62,15 +152,42 @@ impl Display for PithosError {
                 write!(f, "The requested query parameters were invalid: {root_ref}.")
             }
             Self::InvalidRange(start, end, length) => { write!(f, "The requested range, {start}-{end} bytes, is invalid, as the file is only {length} bytes in size.")}
+            Self::ExcessiveRanges => { write!(f, "Too many byte ranges were requested, or too many of them overlap.") }
+            Self::ResumableUnsupported => { write!(f, "The storage server does not support resumable uploads.") }
+            Self::UnsupportedTusVersion => { write!(f, "The requested tus protocol version is not supported. The supported version is {TUS_SUPPORTED_VERSION}.") }
+            Self::OffsetMismatch(given, actual) => { write!(f, "The upload cannot continue from byte {given}, as the server has received {actual} bytes.") }
+            Self::UnsupportedMediaType => { write!(f, "Upload chunks must be sent as application/offset+octet-stream.") }
+            Self::SizeMismatch(declared, sent) => { write!(f, "The upload was declared to be {declared} bytes in size, but {sent} bytes were given.") }
+            Self::ExceedsDeclaredSize(declared) => { write!(f, "The upload exceeded its declared size of {declared} bytes.") }
+            Self::AlreadyExists => { write!(f, "The file being uploaded already exists.") }
+            Self::Locked => { write!(f, "The upload is currently being written to by another request.") }
+            Self::ExpiredUrl(expiry) => { write!(f, "This link expired at {expiry} seconds since the UNIX epoch.") }
+            Self::Gone => { write!(f, "The file being requested has expired or been deleted.") }
+            Self::InvalidDeletionToken => { write!(f, "The deletion token for the file is missing or incorrect.") }
+            Self::InvalidDigest(e) => { write!(f, "The declared digest is invalid: {e}.") }
+            Self::UnsupportedDigest(algorithm) => { write!(f, "The storage server cannot verify {name} digests.", name = algorithm.name()) }
+            Self::DigestMismatch => { write!(f, "The uploaded file does not match its declared digest.") }
+            Self::RateLimited(wait) => { write!(f, "Too many requests. Try again in {seconds} seconds.", seconds = retry_after(*wait)) }
+            Self::Unauthenticated => { write!(f, "A valid API key is required, sent as a bearer token.") }
+            Self::MissingScope(scope) => { write!(f, "The API key does not grant the {name} scope.", name = scope.name()) }
         }
     }
 }
 
+/// Returns the number of whole seconds that a client must wait before retrying, rounded up.
+fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs().saturating_add(u64::from(wait.subsec_nanos() > 0))
+}
+
 impl Error for PithosError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            Self::TooLarge(_, _) | Self::Blocked | Self::NoSuchFile | Self::InvalidRange(_, _, _) => None,
-            Self::Access(e) | Self::ServerError(e) | Self::InvalidQuery(e) => Some(&**e),
+            Self::TooLarge(_, _) | Self::Blocked | Self::NoSuchFile | Self::InvalidRange(_, _, _) | Self::ExcessiveRanges
+                | Self::ResumableUnsupported | Self::UnsupportedTusVersion | Self::OffsetMismatch(_, _)
+                | Self::UnsupportedMediaType | Self::SizeMismatch(_, _) | Self::ExceedsDeclaredSize(_) | Self::AlreadyExists | Self::Locked
+                | Self::ExpiredUrl(_) | Self::Gone | Self::InvalidDeletionToken | Self::UnsupportedDigest(_) | Self::DigestMismatch
+                | Self::RateLimited(_) | Self::Unauthenticated | Self::MissingScope(_) => None,
+            Self::Access(e) | Self::ServerError(e) | Self::InvalidQuery(e) | Self::InvalidDigest(e) => Some(&**e),
         }
     }
 }
@@ -81,6 +198,18 @@ impl From<SignedURLError> for PithosError {
     }
 }
 
+impl From<S3Error> for PithosError {
+    fn from(e: S3Error) -> Self {
+        Self::Access(Box::new(e))
+    }
+}
+
+impl From<rusqlite::Error> for PithosError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::ServerError(Box::new(e))
+    }
+}
+
 impl From<ExtensionError> for PithosError {
     fn from(e: ExtensionError) -> Self {
         Self::InvalidQuery(Box::new(e))
@@ -101,6 +230,30 @@ impl IntoResponse for PithosError {
             error!("{self:?}");
         }
 
-        (code, Json(json!({"error": self.to_string()}))).into_response()
+        let mut body = json!({"error": self.to_string()});
+        if let Some(request_id) = RequestId::current() {
+            body["request_id"] = request_id.as_str().into();
+        }
+
+        let mut response = (code, Json(body)).into_response();
+        response.extensions_mut().insert(ErrorName(self.name()));
+
+        match self {
+            Self::UnsupportedTusVersion => {
+                response.headers_mut().insert(TUS_VERSION, HeaderValue::from_static(TUS_SUPPORTED_VERSION));
+            }
+            Self::InvalidRange(_, _, length) => {
+                response.headers_mut().typed_insert(ContentRange::unsatisfied_bytes(length));
+            }
+            Self::RateLimited(wait) => {
+                response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after(wait)));
+            }
+            Self::Unauthenticated => {
+                response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            _ => (),
+        }
+
+        response
     }
 }
@@ -0,0 +1,64 @@
+//! Reports whether Pithos is alive, and whether it is ready to handle requests, e.g. for Kubernetes probes.
+//!
+//! Readiness is made up of individual checks of the metadata store and the active service,
+//! each of which is reported along with its outcome.
+
+use std::fmt::Display;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::AppState;
+
+/// The outcome of a single readiness check.
+#[derive(Serialize)]
+pub struct Check {
+    /// What was checked.
+    name: &'static str,
+    /// Whether the check passed.
+    ok: bool,
+    /// Details of the outcome, such as why the check failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Check {
+    /// A check that passed, with optional details.
+    pub const fn passed(name: &'static str, detail: Option<String>) -> Self {
+        Self { name, ok: true, detail }
+    }
+
+    /// A check that failed for the given reason.
+    pub fn failed(name: &'static str, reason: impl Display) -> Self {
+        Self { name, ok: false, detail: Some(reason.to_string()) }
+    }
+
+    /// A check that passed if the given result is a success, with the error as its reason otherwise.
+    pub fn from_result<E: Display>(name: &'static str, result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => Self::passed(name, None),
+            Err(e) => Self::failed(name, e),
+        }
+    }
+}
+
+/// Reports that the process is up, without checking anything else.
+pub async fn healthz_handler() -> Json<Value> {
+    Json(json!({"status": "ok"}))
+}
+
+/// Reports whether Pithos is ready to handle requests, with the outcome of every check.
+///
+/// Responds with `503 Service Unavailable` if any of the checks failed.
+pub async fn readyz_handler(State(state): State<&'static AppState>) -> (StatusCode, Json<Value>) {
+    let mut checks = vec![Check::from_result("metadata_store", state.metadata.check().await)];
+    checks.extend(state.service.check_readiness(state.config().min_free_space()).await);
+
+    let ready = checks.iter().all(|check| check.ok);
+    let code = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    (code, Json(json!({"status": if ready { "ready" } else { "unready" }, "checks": checks})))
+}
@@ -0,0 +1,266 @@
+//! Contains the sets of IP address ranges that the IP filter blocks or admits.
+//!
+//! Ranges are stored in a binary prefix trie per address family, so that looking up an address takes
+//! at most one step per bit of the address, however many ranges there are.
+
+use core::fmt::{self, Display, Formatter};
+use core::str::FromStr;
+use std::net::IpAddr;
+
+use serde::Deserialize;
+use serde_with::DeserializeFromStr;
+
+/// A range of IP addresses in CIDR notation, such as `192.0.2.0/24`. A single address is a range of its own.
+#[derive(DeserializeFromStr, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct IpNet {
+    /// The first address of the range.
+    address: IpAddr,
+    /// The number of leading bits shared by every address in the range.
+    prefix_length: u8,
+}
+
+/// The error returned when an IP address range is not in CIDR notation.
+#[derive(Debug)]
+pub struct InvalidIpNet(String);
+
+impl Display for InvalidIpNet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` is not an IP address or a CIDR range", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpNet {}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpNet(s.to_string());
+
+        let (address, prefix_length) = match s.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (s, None),
+        };
+
+        let address = address.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+        let width = address_width(address);
+
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length.parse::<u8>().ok().filter(|&length| length <= width).ok_or_else(invalid)?,
+            None => width,
+        };
+
+        Ok(Self { address, prefix_length })
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+/// Returns the number of bits in an address of the given address's family.
+const fn address_width(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Returns the bits of an address, aligned to the most significant bit.
+fn address_bits(address: IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(address) => u128::from(address.to_bits()) << 96,
+        IpAddr::V6(address) => address.to_bits(),
+    }
+}
+
+/// A node of a prefix trie.
+#[derive(Default)]
+struct Node {
+    /// The indices of the nodes following a zero bit and a one bit.
+    children: [Option<usize>; 2],
+    /// Whether a range ends at this node, so that every address below it is in the set.
+    is_terminal: bool,
+}
+
+/// A binary trie of address prefixes of a single address family.
+struct PrefixTrie {
+    /// The nodes of the trie, starting with the root.
+    nodes: Vec<Node>,
+}
+
+impl Default for PrefixTrie {
+    fn default() -> Self {
+        Self { nodes: vec![Node::default()] }
+    }
+}
+
+impl PrefixTrie {
+    /// Adds the prefix made of the first `length` bits of `bits` to the trie.
+    fn insert(&mut self, bits: u128, length: u8) {
+        let mut current = 0;
+
+        for depth in 0..length {
+            if self.nodes[current].is_terminal {
+                // a shorter prefix already covers this one
+                return;
+            }
+
+            let bit = usize::from((bits >> (127 - depth)) & 1 == 1);
+            current = if let Some(child) = self.nodes[current].children[bit] {
+                child
+            } else {
+                self.nodes.push(Node::default());
+                let child = self.nodes.len() - 1;
+                self.nodes[current].children[bit] = Some(child);
+                child
+            };
+        }
+
+        self.nodes[current].is_terminal = true;
+    }
+
+    /// Returns whether any prefix in the trie is a prefix of the first `width` bits of `bits`.
+    fn contains(&self, bits: u128, width: u8) -> bool {
+        let mut current = 0;
+
+        for depth in 0..width {
+            if self.nodes[current].is_terminal {
+                return true;
+            }
+
+            let bit = usize::from((bits >> (127 - depth)) & 1 == 1);
+            match self.nodes[current].children[bit] {
+                Some(child) => current = child,
+                None => return false,
+            }
+        }
+
+        self.nodes[current].is_terminal
+    }
+}
+
+/// A set of IP address ranges.
+#[derive(Deserialize, Default)]
+#[serde(from = "Vec<IpNet>")]
+pub struct IpSet {
+    /// The IPv4 ranges in the set.
+    v4: PrefixTrie,
+    /// The IPv6 ranges in the set.
+    v6: PrefixTrie,
+    /// The number of ranges in the set.
+    len: usize,
+}
+
+impl From<Vec<IpNet>> for IpSet {
+    fn from(ranges: Vec<IpNet>) -> Self {
+        let mut set = Self::default();
+        for range in ranges {
+            set.insert(range);
+        }
+
+        set
+    }
+}
+
+impl IpSet {
+    /// Adds a range of addresses to the set.
+    pub fn insert(&mut self, range: IpNet) {
+        let trie = match range.address {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        };
+
+        trie.insert(address_bits(range.address), range.prefix_length);
+        self.len += 1;
+    }
+
+    /// Returns whether the address is in any of the ranges in the set.
+    ///
+    /// IPv4-mapped IPv6 addresses are treated as the IPv4 addresses they map.
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        let address = address.to_canonical();
+        let trie = match address {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+
+        trie.contains(address_bits(address), address_width(address))
+    }
+
+    /// Returns whether the set has no ranges.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a set of the given ranges.
+    fn set(ranges: &[&str]) -> IpSet {
+        ranges.iter().map(|range| range.parse().unwrap()).collect::<Vec<IpNet>>().into()
+    }
+
+    /// Returns whether the set contains the given address.
+    fn contains(set: &IpSet, address: &str) -> bool {
+        set.contains(&address.parse().unwrap())
+    }
+
+    #[test]
+    fn overlapping_prefixes_in_either_order() {
+        for ranges in [["10.0.0.0/8", "10.1.0.0/16"], ["10.1.0.0/16", "10.0.0.0/8"]] {
+            let set = set(&ranges);
+            assert!(contains(&set, "10.1.2.3"));
+            assert!(contains(&set, "10.2.3.4"));
+            assert!(!contains(&set, "11.0.0.0"));
+        }
+    }
+
+    #[test]
+    fn zero_length_prefixes() {
+        let set = set(&["0.0.0.0/0"]);
+        assert!(contains(&set, "0.0.0.0"));
+        assert!(contains(&set, "255.255.255.255"));
+        assert!(!contains(&set, "::1"));
+
+        let set = self::set(&["::/0"]);
+        assert!(contains(&set, "2001:db8::1"));
+        assert!(!contains(&set, "192.0.2.1"));
+    }
+
+    #[test]
+    fn full_length_prefixes() {
+        let set = set(&["192.0.2.1/32", "2001:db8::1/128"]);
+        assert!(contains(&set, "192.0.2.1"));
+        assert!(!contains(&set, "192.0.2.0"));
+        assert!(!contains(&set, "192.0.2.2"));
+        assert!(contains(&set, "2001:db8::1"));
+        assert!(!contains(&set, "2001:db8::2"));
+
+        // a single address is a full-length prefix of its own
+        assert!(contains(&self::set(&["192.0.2.1"]), "192.0.2.1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let set = set(&["192.0.2.0/24"]);
+        assert!(contains(&set, "::ffff:192.0.2.1"));
+        assert!(!contains(&set, "::ffff:198.51.100.1"));
+
+        let set = self::set(&["::ffff:192.0.2.1"]);
+        assert!(contains(&set, "192.0.2.1"));
+        assert!(!contains(&set, "::1"));
+    }
+
+    #[test]
+    fn prefixes_longer_than_the_address() {
+        assert!("192.0.2.0/33".parse::<IpNet>().is_err());
+        assert!("2001:db8::/129".parse::<IpNet>().is_err());
+        assert!("192.0.2.0/".parse::<IpNet>().is_err());
+        assert!("192.0.2.0/24".parse::<IpNet>().is_ok());
+    }
+}
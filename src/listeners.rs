@@ -0,0 +1,122 @@
+//! Serves the application on the TCP addresses and Unix domain socket configured in the `[server.listen]` table.
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+
+use axum::Router;
+use axum_server::Handle;
+use axum_server::tls_rustls::RustlsConfig;
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::config::ListenOptions;
+use crate::tls;
+
+/// The address that clients connecting over the Unix domain socket appear to connect from.
+///
+/// Unix domain sockets have no IP addresses, but their clients are always on the same host, such as a reverse proxy.
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Serves the application on every configured address until one of the listeners fails, or until they all shut down.
+///
+/// The TCP listeners shut down through the given handle, and the Unix domain socket once the given token is cancelled.
+/// If TLS is configured, it is used on every TCP address, along with a redirecting plaintext listener on each
+/// of their interfaces if `redirect_port` is set, which redirects to the port of the first address on its interface.
+/// The Unix domain socket always serves plaintext HTTP.
+pub async fn serve(
+    app: Router,
+    options: &ListenOptions,
+    tls: Option<(RustlsConfig, Option<u16>)>,
+    handle: &Handle,
+    stop_listening: &CancellationToken,
+) -> io::Result<()> {
+    let mut servers: Vec<BoxFuture<'static, io::Result<()>>> = Vec::new();
+    // several addresses may share an interface, which only one redirecting listener can bind
+    let mut redirected_ips = HashSet::new();
+
+    for &addr in options.addresses() {
+        let service = app.clone().into_make_service_with_connect_info::<SocketAddr>();
+
+        if let Some((tls_config, redirect_port)) = &tls {
+            if let Some(redirect_port) = *redirect_port && redirected_ips.insert(addr.ip()) {
+                let redirect_addr = SocketAddr::new(addr.ip(), redirect_port);
+                tokio::spawn(async move {
+                    if let Err(e) = tls::redirect_to_https(redirect_addr, addr.port()).await {
+                        error!("Failed to redirect plaintext HTTP on {redirect_addr}: {e}");
+                    }
+                });
+            }
+
+            info!("Listening on {addr} (HTTPS)");
+            servers.push(axum_server::bind_rustls(addr, tls_config.clone()).handle(handle.clone()).serve(service).boxed());
+        } else {
+            info!("Listening on {addr}");
+            servers.push(axum_server::bind(addr).handle(handle.clone()).serve(service).boxed());
+        }
+    }
+
+    if let Some(path) = options.unix_socket() {
+        servers.push(serve_unix(app, path, stop_listening.clone())?);
+    }
+
+    future::try_join_all(servers).await?;
+    Ok(())
+}
+
+/// Starts serving the application on a Unix domain socket, replacing any socket left behind at its path.
+///
+/// Clients on the socket appear to connect from [`UNIX_PEER`], so a reverse proxy in front of it should
+/// pass on the client's address in one of the headers that `ip_source` supports.
+#[cfg(unix)]
+fn serve_unix(app: Router, path: &Path, stop_listening: CancellationToken) -> io::Result<BoxFuture<'static, io::Result<()>>> {
+    use axum::extract::ConnectInfo;
+    use axum::Extension;
+    use tokio::net::UnixListener;
+
+    // a socket left behind by a previous run, such as one that crashed, would make binding fail
+    remove_socket(path)?;
+
+    let listener = UnixListener::bind(path)?;
+    info!("Listening on {path}", path = path.display());
+
+    let accept = hyper::server::accept::poll_fn(move |cx| {
+        listener.poll_accept(cx).map(|result| Some(result.map(|(stream, _)| stream)))
+    });
+
+    let service = app.layer(Extension(ConnectInfo(UNIX_PEER))).into_make_service();
+    Ok(hyper::Server::builder(accept).serve(service)
+        .with_graceful_shutdown(stop_listening.cancelled_owned())
+        .map(|result| result.map_err(io::Error::other)).boxed())
+}
+
+/// Removes the Unix domain socket at the given path, if there is one.
+///
+/// Fails instead of removing any other kind of file, so that a misconfigured path can't delete something else.
+#[cfg(unix)]
+pub fn remove_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Does nothing, as Unix domain sockets are only supported on Unix.
+#[cfg(not(unix))]
+pub fn remove_socket(_: &std::path::Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Fails, as Unix domain sockets are only supported on Unix.
+#[cfg(not(unix))]
+fn serve_unix(_: Router, path: &std::path::Path, _: CancellationToken) -> io::Result<BoxFuture<'static, io::Result<()>>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("can't listen on {}, as Unix domain sockets are only supported on Unix", path.display())))
+}
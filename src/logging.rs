@@ -0,0 +1,210 @@
+//! Writes the logs of Pithos, and ties together everything logged about a request with a request ID.
+//!
+//! Every request is given an ID, or keeps the one it was sent with in `X-Request-Id`. The ID is echoed in the
+//! response, included in error bodies and attached to everything logged while the request is handled. Once the
+//! response has been sent, or abandoned by the client, a line is written to the access log.
+
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use std::net::IpAddr;
+
+use axum::body::{boxed, Body, BoxBody, Bytes, HttpBody};
+use axum::extract::MatchedPath;
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_client_ip::SecureClientIp;
+use hyper::body::SizeHint;
+use tokio::time::Instant;
+use opentelemetry_sdk::trace::Tracer;
+use tracing::field::Empty;
+use tracing::{info, info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use uuid::Uuid;
+
+use crate::config::LogFormat;
+use crate::custom_headers::X_REQUEST_ID;
+use crate::telemetry;
+
+/// The target of the access log, so that it can be filtered separately with `RUST_LOG`.
+const ACCESS_LOG: &str = "pithos::access";
+
+/// The longest request ID that is accepted from clients, in bytes.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    /// The ID of the request that the current task is handling.
+    static REQUEST_ID: RequestId;
+}
+
+/// Starts writing logs to standard output in the given format, and exporting spans with the given tracer, if any.
+///
+/// Everything at the `INFO` level and above is logged, unless the `RUST_LOG` environment variable says otherwise.
+pub fn init(format: LogFormat, tracer: Option<Tracer>) {
+    let filter = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into()).from_env_lossy();
+    let output = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(output)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .with(filter)
+        .init();
+}
+
+/// The ID of a request.
+#[derive(Clone)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Returns the ID that the client sent in `X-Request-Id`, or a new one if it sent none or an unusable one.
+    ///
+    /// IDs from clients must be at most [`MAX_REQUEST_ID_LENGTH`] bytes of printable ASCII, so that they can't
+    /// break up log lines or flood them.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        headers.get(X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| (1..=MAX_REQUEST_ID_LENGTH).contains(&id.len()) && id.bytes().all(|byte| byte.is_ascii_graphic()))
+            .map_or_else(|| Self(Uuid::new_v4().to_string()), |id| Self(id.to_string()))
+    }
+
+    /// Returns the ID of the request that the current task is handling, if it is handling one.
+    pub fn current() -> Option<Self> {
+        REQUEST_ID.try_with(Clone::clone).ok()
+    }
+
+    /// Returns the ID as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Gives every request an ID, which is echoed in the `X-Request-Id` header of its response.
+///
+/// The request is handled in a span carrying the ID, which continues the trace that the request was sent with,
+/// and the ID is kept in the request's extensions.
+pub async fn assign_request_id(mut request: Request<Body>, next: Next<Body>) -> Response {
+    let id = RequestId::from_headers(request.headers());
+    request.extensions_mut().insert(id.clone());
+
+    let route = request.extensions().get::<MatchedPath>().map_or_else(|| request.uri().path(), MatchedPath::as_str);
+    let span = info_span!(
+        "request",
+        id = id.as_str(),
+        otel.name = format!("{method} {route}", method = request.method()),
+        otel.kind = "server",
+        http.request.method = %request.method(),
+        http.route = route,
+        http.response.status_code = Empty,
+        api_key = Empty,
+    );
+
+    // without a trace exporter, there is no trace to continue
+    let _ = span.set_parent(telemetry::remote_context(request.headers()));
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+
+    // the ID is printable ASCII whether it was generated or given, so it is always a valid header value
+    if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+        response.headers_mut().insert(X_REQUEST_ID, value);
+    }
+
+    response
+}
+
+/// A line of the access log, which is written once it is dropped along with the response body.
+struct AccessLogEntry {
+    /// The ID of the request, if it was given one.
+    request_id: Option<RequestId>,
+    /// The client's IP address, if it could be determined.
+    client_ip: Option<IpAddr>,
+    /// The method of the request.
+    method: Method,
+    /// The route that the request matched, or its path if it matched none.
+    route: String,
+    /// The status code of the response.
+    status: StatusCode,
+    /// The number of bytes of the response body that have been sent.
+    bytes: u64,
+    /// When the request was received.
+    started_at: Instant,
+}
+
+impl Drop for AccessLogEntry {
+    fn drop(&mut self) {
+        info!(
+            target: ACCESS_LOG,
+            request_id = self.request_id.as_ref().map(RequestId::as_str),
+            client_ip = self.client_ip.map(display),
+            method = %self.method,
+            route = self.route.as_str(),
+            status = self.status.as_u16(),
+            bytes = self.bytes,
+            duration_ms = self.started_at.elapsed().as_secs_f64() * 1000.0,
+            "Finished request",
+        );
+    }
+}
+
+/// A response body that counts the bytes sent into its access log entry.
+struct CountedBody {
+    /// The response body being sent.
+    body: BoxBody,
+    /// The entry that is written once the body is dropped.
+    entry: AccessLogEntry,
+}
+
+impl HttpBody for CountedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.body).poll_data(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.entry.bytes += chunk.len() as u64;
+        }
+
+        poll
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Writes a line to the access log for every request, with the client's IP address, the route, the status code,
+/// the number of bytes sent and how long the request took.
+///
+/// The line is written once the response body has been sent in full or dropped, so that the bytes and duration
+/// of downloads are accurate.
+pub async fn log_access(client_ip: Option<SecureClientIp>, request: Request<Body>, next: Next<Body>) -> Response {
+    let mut entry = AccessLogEntry {
+        request_id: request.extensions().get::<RequestId>().cloned(),
+        client_ip: client_ip.map(|SecureClientIp(ip)| ip),
+        method: request.method().clone(),
+        route: request.extensions().get::<MatchedPath>().map_or_else(|| request.uri().path(), MatchedPath::as_str).to_string(),
+        status: StatusCode::OK,
+        bytes: 0,
+        started_at: Instant::now(),
+    };
+
+    let response = next.run(request).await;
+    entry.status = response.status();
+
+    response.map(|body| boxed(CountedBody { body, entry }))
+}
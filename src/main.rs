@@ -12,99 +12,259 @@
 #![allow(clippy::multiple_crate_versions)]
 
 
-use std::collections::Bound;
-use std::io::{Error, ErrorKind, SeekFrom};
+use core::time::Duration;
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::ops::RangeInclusive;
 
 use serde_with::{serde_as, DisplayFromStr};
 
-use axum::{extract::{Path, State}, http::{method::Method, StatusCode}, Json, middleware, Router, routing::get, TypedHeader};
-use axum::extract::{BodyStream, Query, FromRequestParts};
+use arc_swap::ArcSwap;
+use axum_server::Handle;
+use axum::{extract::{Path, State}, http::{method::Method, StatusCode}, Extension, Json, middleware, Router, routing::get, TypedHeader};
+use axum::extract::{BodyStream, MatchedPath, Query, FromRequestParts};
 use axum::headers::{self, HeaderMapExt};
 use axum::http::{HeaderMap, HeaderValue, Request};
 use axum::middleware::Next;
-use axum::response::Response;
-use axum::routing::put;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, post, put};
 use axum_client_ip::SecureClientIp;
-use axum_signed_urls::SignedUrl;
-use futures::{Stream, StreamExt, TryStreamExt};
+use futures::{StreamExt, TryStreamExt};
 use google_cloud_storage::client::{Client, ClientConfig};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
-use tracing::info;
+use tower_http::set_header::SetResponseHeaderLayer;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, Instrument};
 use uuid::Uuid;
 
 use mime::Mime;
 
-use crate::config::Config;
-use crate::custom_headers::{X_FILE_SIZE, XFileSize};
+use crate::config::{Config, ListenOptions};
+use crate::custom_headers::{TUS_EXTENSION, TUS_MAX_SIZE, TUS_RESUMABLE, TUS_VERSION, UPLOAD_LENGTH, UPLOAD_METADATA, UPLOAD_OFFSET, DIGEST, REPR_DIGEST, X_DELETION_TOKEN, X_FILE_DIGEST, X_FILE_SIZE, X_FILE_TTL, X_MAX_DOWNLOADS, X_REQUEST_ID, XFileSize, XFileTtl, XMaxDownloads};
+use crate::api_keys::{ApiKey, Scope};
+use crate::digests::{ContentDigest, DigestQuery, Hasher, MalformedDigest};
 use crate::errors::PithosError;
-use crate::service::{AvailableService, DownloadHandle, GoogleCloudStorage, LocalStorage, Service, UploadHandle};
+use crate::service::{AvailableService, DownloadHandle, GoogleCloudStorage, LocalStorage, S3Storage, Service, UploadHandle};
 use crate::file_extensions::FileExt;
+use crate::metadata::{MetadataStore, NewObject, ObjectRecord};
+use crate::metrics::Metrics;
+use crate::ranges::{ByteRange, Coverage, Multipart};
+use crate::rate_limits::{RateLimiter, RateLimiters};
+use crate::validators::Validators;
+use crate::signed_urls::UnexpiredSignedUrl;
+use crate::tus::{TUS_ENDPOINT, TUS_SUPPORTED_VERSION};
 
 mod errors;
 mod service;
 mod config;
 mod file_extensions;
 mod custom_headers;
+mod tus;
+mod staging;
+mod signed_urls;
+mod metadata;
+mod collector;
+mod secrets;
+mod digests;
+mod rate_limits;
+mod ip_filter;
+mod api_keys;
+mod reload;
+mod tls;
+mod listeners;
+mod shutdown;
+mod metrics;
+mod logging;
+mod telemetry;
+mod health;
+mod ranges;
+mod validators;
+
+/// The path of the configuration file.
+const CONFIG_PATH: &str = "Config.toml";
 
 /// Represents the state of the application at any given time.
 struct AppState {
     /// The service used to generate URLs for accessing files
     service: Box<dyn Service>,
-    /// The configuration of the application
-    config: Config,
+    /// The configuration of the application, which is swapped out whenever it is reloaded
+    config: ArcSwap<Config>,
+    /// The store recording the metadata of every file
+    metadata: MetadataStore,
+    /// The limiters of how quickly clients may request URLs and transfer files
+    rate_limiters: RateLimiters,
+    /// The bytes of each file with a download limit that have been sent from the local storage, for counting downloads
+    coverage: Coverage,
+    /// The metrics of how Pithos is used
+    metrics: Metrics,
+}
+
+impl AppState {
+    /// Returns the current configuration of the application.
+    ///
+    /// Requests should hold on to the returned configuration, so that a reload halfway through doesn't affect them.
+    fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
     let _ = dotenv::dotenv();
 
-    let config = initialise_config().await?;
+    let (config, config_table) = initialise_config().await?;
+
+    let tracer_provider = initialise_logging(&config)?;
 
     let service: Box<dyn Service> = match config.chosen_service() {
-        AvailableService::LocalStorage => { Box::new(LocalStorage::new("/signed_upload", "/signed_download")) }
+        AvailableService::LocalStorage => { Box::new(LocalStorage::new("/signed_upload", "/signed_download", TUS_ENDPOINT, config.local_storage_path(), config.staging_path())) }
         AvailableService::GoogleCloudStorage => { Box::new(initialise_gcs_service(&config).await?) }
+        AvailableService::S3 => { Box::new(initialise_s3_service(&config)?) }
     };
 
     info!("Initialised {service} Service");
 
+    let metadata = MetadataStore::open(&config.metadata_path())?;
+    info!("Opened metadata store at {path}", path = config.metadata_path().display());
+
+    let rate_limiters = RateLimiters {
+        requests: RateLimiter::new(config.request_rate_limit()),
+        bytes: RateLimiter::new(config.byte_rate_limit()),
+    };
+
     // app state lives for the lifetime of the program — it is 'effectively static' so fine to leak
-    let state: &'static AppState = Box::leak(Box::new(AppState { service, config }));
+    let state: &'static AppState = Box::leak(Box::new(AppState {
+        service, config: ArcSwap::from_pointee(config), metadata, rate_limiters, coverage: Coverage::default(), metrics: Metrics::new()?,
+    }));
+
+    tokio::spawn(collector::collect_garbage(state));
+    tokio::spawn(reload::watch(state, config_table));
+
+    if let Some(metrics_address) = state.config().metrics_address() {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(state, metrics_address).await {
+                error!("Failed to serve metrics on {metrics_address}: {e}");
+            }
+        });
+    }
 
     let app = Router::new()
         .route("/upload", get(upload_handler))
         .route("/download/:uuid", get(download_handler))
+        .route("/files/:uuid", delete(delete_handler))
+        .route("/files/:uuid/info", get(info_handler))
         .route("/signed_upload/:uuid", put(signed_upload_handler))
         .route("/signed_download/:uuid", get(signed_download_handler))
+        .route(&format!("{TUS_ENDPOINT}/:uuid"), post(tus::create_handler)
+            .head(tus::offset_handler)
+            .patch(tus::append_handler)
+            .delete(tus::terminate_handler)
+            .layer(SetResponseHeaderLayer::overriding(TUS_RESUMABLE, HeaderValue::from_static(TUS_SUPPORTED_VERSION))))
         .layer(ServiceBuilder::new()
-            .layer(state.config.get_ip_source().into_extension())
+            .layer(state.config().get_ip_source().into_extension())
+            .layer(middleware::from_fn(logging::assign_request_id))
+            .layer(middleware::from_fn(logging::log_access))
+            .layer(middleware::from_fn_with_state(state, metrics::record))
+            .layer(middleware::from_fn_with_state(state, tus::describe))
+            // CORS wraps the checks, so that preflights pass without credentials and refusals are readable by browsers
+            .layer(cors_layer())
             .layer(middleware::from_fn_with_state(state, filter_ips))
-            .layer(cors_layer()))
+            .layer(middleware::from_fn_with_state(state, authenticate))
+            .layer(middleware::from_fn_with_state(state, rate_limits::limit_rates)))
+        // the probes are added after the layers, so that they aren't subject to the IP filter or rate limits
+        .route("/healthz", get(health::healthz_handler))
+        .route("/readyz", get(health::readyz_handler))
         .with_state(state);
 
-    let port = match std::env::var("PORT") {
-        Ok(port_choice) => Some(port_choice.parse::<u16>()?),
-        Err(_) => None,
+    let listen_options = if let Some(listen_options) = state.config().listen_options() {
+        listen_options
+    } else {
+        let port = match std::env::var("PORT") {
+            Ok(port_choice) => Some(port_choice.parse::<u16>()?),
+            Err(_) => None,
+        };
+
+        if port.is_none() {
+            info!("Listening on port 8080 by default (change with the PORT environment variable or the `server.listen` table)");
+        }
+
+        ListenOptions::tcp(SocketAddr::from(([0, 0, 0, 0], port.unwrap_or(8080))))
     };
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], port.unwrap_or(8080)));
+    if listen_options.is_empty() {
+        return Err("`server.listen` must have at least one address or a Unix socket".into());
+    }
+
+    let tls = match state.config().tls_options() {
+        Some(tls_options) => {
+            let tls_config = tls::load(&tls_options).await?;
+            info!("Serving HTTPS with the certificate from {path}", path = tls_options.cert_path().display());
+
+            let redirect_port = tls_options.redirect_port();
+            tokio::spawn(tls::watch(tls_options, tls_config.clone()));
+            Some((tls_config, redirect_port))
+        }
+        None => None,
+    };
+
+    let handle = Handle::new();
+    let stop_listening = CancellationToken::new();
+    let servers = listeners::serve(app, &listen_options, tls, &handle, &stop_listening);
+    tokio::pin!(servers);
+
+    tokio::select! {
+        result = &mut servers => return Ok(result?),
+        () = shutdown::requested() => {}
+    }
+
+    let grace_period = state.config().shutdown_grace_period();
+    info!("Shutting down, waiting up to {grace_period:?} for {count} connections to finish", count = handle.connection_count());
+
+    handle.graceful_shutdown(Some(grace_period));
+    stop_listening.cancel();
+
+    if tokio::time::timeout(grace_period, servers).await.is_err() {
+        info!("Grace period ended, abandoning the remaining connections");
+    }
 
-    info!("Listening on {addr}{change_suggest}", change_suggest = if port.is_some() { "" } else { " (change with the PORT environment variable)" });
+    shutdown::clean_up(state).await;
 
+    if let Some(tracer_provider) = tracer_provider {
+        telemetry::shutdown(tracer_provider).await;
+    }
 
-    Ok(axum_server::bind(addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await?)
+    Ok(())
 }
 
-/// Parses the `Config.toml` file and returns a `Config` struct.
-async fn initialise_config() -> Result<Config, Box<dyn std::error::Error>> {
+/// Parses the `Config.toml` file and returns a `Config` struct, along with the TOML table it was parsed from.
+///
+/// The keys in the API key file, if there is one, are read into the configuration as well.
+async fn initialise_config() -> Result<(Config, toml::Table), Box<dyn std::error::Error>> {
     use tokio::fs;
-    let config_text = fs::read_to_string("Config.toml").await?;
-    Ok(toml::from_str(&config_text)?)
+    let config_text = fs::read_to_string(CONFIG_PATH).await?;
+    let mut config: Config = toml::from_str(&config_text)?;
+
+    if let Some(key_file) = config.api_key_file() {
+        config.add_api_keys(api_keys::read_key_file(&key_file).await?);
+    }
+
+    Ok((config, toml::from_str(&config_text)?))
+}
+
+/// Starts writing logs in the configured format and, if traces are exported, returns the provider that exports them.
+fn initialise_logging(config: &Config) -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
+    let tracer_provider = config.tracing_options().map(|options| telemetry::tracer_provider(&options)).transpose()?;
+    logging::init(config.log_format(), tracer_provider.as_ref().map(|provider| provider.tracer("pithos")));
+
+    if let Some(tracing_options) = config.tracing_options() {
+        info!("Exporting traces to {endpoint}", endpoint = tracing_options.otlp_endpoint());
+    }
+
+    Ok(tracer_provider)
 }
 
 /// Initialises the Google Cloud Storage Service, using the `GOOGLE_APPLICATION_CREDENTIALS` or `GOOGLE_APPLICATION_CREDENTIALS_JSON` environment variables.
@@ -117,37 +277,138 @@ async fn initialise_gcs_service(config: &Config) -> Result<GoogleCloudStorage, B
     Ok(service)
 }
 
+/// Initialises the S3-compatible storage service, using the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment variables.
+fn initialise_s3_service(config: &Config) -> Result<S3Storage, Box<dyn std::error::Error>> {
+    use s3::{Bucket, Region};
+    use s3::creds::Credentials;
+
+    let s3_config = config.s3_config().ok_or("the S3 service was chosen, but `services.s3` is not configured")?;
+
+    let region = Region::Custom { region: s3_config.region(), endpoint: s3_config.endpoint() };
+    let mut bucket = Bucket::new(&s3_config.bucket_name(), region, Credentials::from_env()?)?;
+    if s3_config.path_style() {
+        bucket = bucket.with_path_style();
+    }
+
+    Ok(S3Storage::with_bucket(bucket))
+}
+
 /// Configures CORS for the application.
 fn cors_layer() -> CorsLayer {
     CorsLayer::new()
-        .allow_methods([Method::HEAD, Method::GET, Method::PUT])
-        .allow_headers(vec![X_FILE_SIZE.into(), CONTENT_TYPE])
+        .allow_methods([Method::HEAD, Method::GET, Method::PUT, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_headers(vec![X_DELETION_TOKEN, X_FILE_DIGEST, X_FILE_SIZE, X_FILE_TTL, X_MAX_DOWNLOADS, X_REQUEST_ID, AUTHORIZATION, CONTENT_TYPE, RANGE, IF_RANGE, IF_NONE_MATCH, IF_MODIFIED_SINCE,
+            TUS_RESUMABLE, UPLOAD_LENGTH, UPLOAD_OFFSET, UPLOAD_METADATA])
+        .expose_headers(vec![LOCATION, RETRY_AFTER, WWW_AUTHENTICATE, CONTENT_RANGE, ACCEPT_RANGES, ETAG, REPR_DIGEST, DIGEST, TUS_RESUMABLE, TUS_VERSION, TUS_EXTENSION, TUS_MAX_SIZE, UPLOAD_LENGTH, UPLOAD_OFFSET, X_REQUEST_ID])
         .allow_origin(Any)
 }
 
 /// Filters out requests from blocked IPs.
 async fn filter_ips<B: Send>(State(state): State<&'static AppState>, SecureClientIp(ip): SecureClientIp, request: Request<B>, next: Next<B>) -> Result<Response, PithosError> {
-    if state.config.is_blocked(&ip) {
+    if state.config().is_blocked(&ip) {
+        state.metrics.record_blocked();
         return Err(PithosError::Blocked);
     }
 
     Ok(next.run(request).await)
 }
 
+/// Refuses requests without an API key that grants the scope their route requires, unless anonymous clients have it.
+///
+/// A request that sends a key must send a valid one, even if it wouldn't need one. The key that a request
+/// was authenticated with is kept in its extensions.
+async fn authenticate<B: Send>(
+    State(state): State<&'static AppState>,
+    bearer: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, PithosError> {
+    let config = state.config();
+    let Some(authentication) = config.authentication() else { return Ok(next.run(request).await) };
+
+    let route = request.extensions().get::<MatchedPath>().map_or_else(|| request.uri().path(), MatchedPath::as_str);
+    let Some(scope) = Scope::required_for(request.method(), route) else { return Ok(next.run(request).await) };
+
+    let api_key = match bearer {
+        Some(TypedHeader(headers::Authorization(bearer))) => Some(authentication.keys().find(bearer.token()).ok_or(PithosError::Unauthenticated)?),
+        None => None,
+    };
+
+    match api_key {
+        Some(api_key) if api_keys::allows(api_key.scopes(), scope) => {
+            tracing::Span::current().record("api_key", api_key.name());
+            request.extensions_mut().insert(api_key.clone());
+        }
+        Some(_) => return Err(PithosError::MissingScope(scope)),
+        None if api_keys::allows(authentication.anonymous_scopes(), scope) => {}
+        None => return Err(PithosError::Unauthenticated),
+    }
+
+    Ok(next.run(request).await)
+}
+
 /// Handles requests to upload a file, redirecting them to the service.
+///
+/// If the request has a `Tus-Resumable` header, the returned URL is a tus upload endpoint.
 #[axum::debug_handler]
 async fn upload_handler(
     State(state): State<&'static AppState>,
+    SecureClientIp(ip): SecureClientIp,
     TypedHeader(file_size): TypedHeader<XFileSize>,
+    requested_ttl: Option<TypedHeader<XFileTtl>>,
+    max_downloads: Option<TypedHeader<XMaxDownloads>>,
+    headers: HeaderMap,
 ) -> Result<(StatusCode, Json<UploadHandle>), PithosError> {
-    let AppState { config, service } = state;
+    let AppState { service, metadata, .. } = state;
+    let config = state.config();
 
-    if (file_size.0) > config.max_upload_size() {
-        return Err(PithosError::TooLarge(file_size.0, config.max_upload_size()));
-    }
+    info_span!("check_upload_size", size = file_size.0).in_scope(|| {
+        if (file_size.0) > config.max_upload_size() {
+            return Err(PithosError::TooLarge(file_size.0, config.max_upload_size()));
+        }
+
+        Ok(())
+    })?;
+
+    // a malformed digest is refused rather than ignored, as the client relies on it to detect corruption
+    let digest = headers.get(X_FILE_DIGEST)
+        .map(|digest| digest.to_str().map_err(|_| MalformedDigest)?.parse::<ContentDigest>())
+        .transpose()
+        .map_err(|e| PithosError::InvalidDigest(Box::new(e)))?;
+
+    // the lifetimes are read on every request, so that reloading the configuration affects the next URL
+    let lifetimes = config.url_lifetimes();
+    let (handle, lifetime) = if headers.contains_key(TUS_RESUMABLE) {
+        tus::check_version(&headers)?;
+        (service.request_resumable_upload_url(file_size.0, digest.as_ref(), lifetimes.resumable_upload).await?, lifetimes.resumable_upload)
+    } else {
+        (service.request_upload_url(file_size.0, digest.as_ref(), lifetimes.upload).await?, lifetimes.upload)
+    };
+
+    state.metrics.record_signed_url(&**service, "upload");
 
-    service.request_upload_url(file_size.0).await
-        .map(|handle| (StatusCode::CREATED, Json(handle)))
+    let ttl = requested_ttl.map(|TypedHeader(XFileTtl(seconds))| Duration::from_secs(seconds)).or_else(|| config.default_ttl());
+    let ttl = match (ttl, config.max_ttl()) {
+        (Some(ttl), Some(max_ttl)) => Some(ttl.min(max_ttl)),
+        (ttl, _) => ttl,
+    };
+
+    let expires_at = ttl.map(|ttl| metadata::now().saturating_add(ttl.as_secs()));
+    // a file that can be downloaded zero times is pointless, so it is treated as burn-after-reading instead
+    let max_downloads = max_downloads.map(|TypedHeader(XMaxDownloads(count))| count.max(1));
+
+    metadata.record_issued(NewObject {
+        uuid: handle.uuid,
+        size: file_size.0,
+        uploader_ip: ip,
+        expires_at,
+        upload_expires_at: metadata::now().saturating_add(lifetime.as_secs()),
+        max_downloads,
+        deletion_token_hash: secrets::hash(&handle.deletion_token),
+        digest,
+    }).await?;
+
+    Ok((StatusCode::CREATED, Json(handle)))
 }
 
 #[serde_as]
@@ -165,123 +426,392 @@ pub struct DownloadQuery {
 pub struct QueryExtractor<T>(pub T);
 
 /// Handles requests to download a file, redirecting them to the service.
+///
+/// Files that were never issued an upload URL, or that haven't been uploaded in full, can't be downloaded.
 #[axum::debug_handler]
 async fn download_handler(
     State(state): State<&'static AppState>,
     Path(uuid): Path<Uuid>,
     QueryExtractor(options): QueryExtractor<DownloadQuery>
 ) -> Result<Json<DownloadHandle>, PithosError> {
-    let handle = state.service.request_download_url(options.type_hint, options.ext_hint, uuid).await?;
+    let AppState { service, metadata, .. } = state;
+    let config = state.config();
+
+    let record = find_record(state, uuid).await?.ok_or(PithosError::NoSuchFile)?;
+    if record.is_gone() {
+        return Err(PithosError::Gone);
+    }
+
+    if !record.is_complete() {
+        // uploads to external services bypass Pithos, so their completion is only noticed here
+        if !service.has_object(uuid).await? {
+            return Err(PithosError::NoSuchFile);
+        }
+
+        metadata.record_completed(uuid).await?;
+    }
+
+    let lifetime = config.url_lifetimes().download;
+    let handle = service.request_download_url(options.type_hint, options.ext_hint, uuid, lifetime).await?;
+    state.metrics.record_signed_url(&**service, "download");
+
+    if !service.counts_downloads() && metadata.record_download(uuid).await? == Some(0) {
+        // the last download URL stays usable until it expires, so the file is left for the garbage collector
+        metadata.expire_at(uuid, metadata::now().saturating_add(lifetime.as_secs())).await?;
+    }
+
     Ok(Json(handle))
 }
 
+/// Represents a response to a file information request.
+#[derive(Serialize)]
+pub struct FileInfo {
+    /// The size of the file, in bytes.
+    size: u64,
+    /// The UNIX timestamp, in seconds, at which the file was uploaded.
+    created_at: Option<u64>,
+    /// The UNIX timestamp, in seconds, after which the file is deleted, if it has a time-to-live.
+    expires_at: Option<u64>,
+    /// How many more times the file may be downloaded, if the number of downloads is limited.
+    downloads_remaining: Option<u64>,
+    /// The digest that the uploader declared for the file, in the format of the `Repr-Digest` header.
+    digest: Option<String>,
+}
+
+/// Handles requests for the size, age, and remaining lifetime of a file.
+///
+/// Like downloads, this is only available for files that have been uploaded in full and haven't expired.
+#[axum::debug_handler]
+async fn info_handler(
+    State(state): State<&'static AppState>,
+    Path(uuid): Path<Uuid>,
+) -> Result<Json<FileInfo>, PithosError> {
+    let AppState { service, metadata, .. } = state;
+
+    let record = find_record(state, uuid).await?.ok_or(PithosError::NoSuchFile)?;
+    if record.is_gone() {
+        return Err(PithosError::Gone);
+    }
+
+    let stat = service.stat(uuid).await?.ok_or(PithosError::NoSuchFile)?;
+    if !record.is_complete() {
+        metadata.record_completed(uuid).await?;
+    }
+
+    Ok(Json(FileInfo {
+        size: stat.size,
+        created_at: stat.created_at.or(record.completed_at),
+        expires_at: record.expires_at,
+        downloads_remaining: record.downloads_remaining,
+        digest: record.digest.map(|digest| digest.to_string()),
+    }))
+}
+
+/// Handles requests to delete a file, authorised by the deletion token issued along with its upload URL.
+#[axum::debug_handler]
+async fn delete_handler(
+    State(state): State<&'static AppState>,
+    Path(uuid): Path<Uuid>,
+    api_key: Option<Extension<ApiKey>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, PithosError> {
+    let AppState { service, metadata, .. } = state;
+
+    let record = find_record(state, uuid).await?.ok_or(PithosError::NoSuchFile)?;
+
+    // admins may delete any file, such as one that was reported, without knowing its deletion token
+    let is_admin = api_key.is_some_and(|Extension(api_key)| api_keys::allows(api_key.scopes(), Scope::Admin));
+    let token = headers.get(X_DELETION_TOKEN).and_then(|token| token.to_str().ok());
+    let is_authorised = is_admin || token.zip(record.deletion_token_hash.as_deref())
+        .is_some_and(|(token, expected_hash)| secrets::hash(token) == expected_hash);
+    if !is_authorised {
+        return Err(PithosError::InvalidDeletionToken);
+    }
+
+    if record.deleted_at.is_some() {
+        return Err(PithosError::Gone);
+    }
+
+    // the deletion is recorded first, so that an upload finishing in the meantime discards the file itself
+    metadata.record_deleted(uuid).await?;
+    service.delete(uuid).await?;
+    state.coverage.forget(uuid);
+    if is_admin {
+        info!("Deleted file {uuid} at the request of an admin");
+    } else {
+        info!("Deleted file {uuid} at the request of its uploader");
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns the metadata of a file, or `None` if it is neither recorded nor stored.
+///
+/// Files that were stored before the metadata store existed have no record, so they are recorded as they are
+/// found in the storage, and stay available without a time-to-live or download limit.
+async fn find_record(state: &AppState, uuid: Uuid) -> Result<Option<ObjectRecord>, PithosError> {
+    if let Some(record) = state.metadata.get(uuid).await? {
+        return Ok(Some(record));
+    }
+
+    let Some(stat) = state.service.stat(uuid).await? else {
+        return Ok(None);
+    };
+
+    state.metadata.record_existing(uuid, stat).await?;
+    info!("Recorded file {uuid}, which was stored before the metadata store existed");
+    state.metadata.get(uuid).await
+}
+
+/// Counts a completed download of a file, deleting the file once it has been downloaded as many times as allowed.
+async fn record_download(state: &AppState, uuid: Uuid) -> Result<(), PithosError> {
+    state.coverage.forget(uuid);
+    if state.metadata.record_download(uuid).await? == Some(0) {
+        state.service.delete(uuid).await?;
+        state.metadata.record_deleted(uuid).await?;
+        info!("Deleted file {uuid} after its last allowed download");
+    }
+
+    Ok(())
+}
+
+/// The progress of sending a file from the local storage, which is recorded in [`AppState::coverage`] once the
+/// response body has been sent in full or dropped.
+struct Delivery {
+    state: &'static AppState,
+    uuid: Uuid,
+    /// The size of the file.
+    total_file_size: u64,
+    /// The length of the response body.
+    size: u64,
+    /// The ranges of the file that the body carries, in order, or `None` if it carries the whole file.
+    ranges: Option<Vec<ByteRange>>,
+    /// The number of bytes of the body that have been sent.
+    sent: u64,
+}
+
+impl Delivery {
+    /// Returns the ranges of the file that have been sent.
+    ///
+    /// A single range or the whole file is sent as is, so an interrupted transfer still covers the bytes before
+    /// it stopped, while the ranges of a multipart body only count once all of them have been sent.
+    fn sent_ranges(&self) -> Vec<ByteRange> {
+        match self.ranges.as_deref() {
+            _ if self.sent == 0 => Vec::new(),
+            None => vec![(0, self.sent - 1)],
+            Some(&[(first_byte, _)]) => vec![(first_byte, first_byte + self.sent - 1)],
+            Some(ranges) if self.sent == self.size => ranges.to_vec(),
+            Some(_) => Vec::new(),
+        }
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        if !self.state.coverage.record(self.uuid, &self.sent_ranges(), self.total_file_size) {
+            return;
+        }
+
+        let (state, uuid) = (self.state, self.uuid);
+        tokio::spawn(async move {
+            if let Err(e) = record_download(state, uuid).await {
+                error!("Failed to record download of file {uuid}: {e:?}");
+            }
+        });
+    }
+}
+
+/// The signed query parameters of a local upload URL.
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    /// The size of the file, as declared when the URL was requested.
+    length: u64,
+}
+
 /// Handles requests to upload a file to the local Pithos storage.
+///
+/// The file is written to the staging directory and only moved into the local storage once it is complete.
+/// The upload is aborted and the partial file removed if the body is not exactly as long as declared.
 #[axum::debug_handler]
 async fn signed_upload_handler(
     State(state): State<&'static AppState>,
-    _: SignedUrl,
+    _: UnexpiredSignedUrl,
     Path(uuid): Path<Uuid>,
+    Query(query): Query<UploadQuery>,
+    Query(digest): Query<DigestQuery>,
+    content_length: Option<TypedHeader<headers::ContentLength>>,
     body: BodyStream
 ) -> Result<StatusCode, PithosError> {
     use tokio::fs;
+    use tokio::io::AsyncReadExt;
     use tokio_util::io::StreamReader;
 
-    let AppState { config, .. } = state;
+    let config = state.config();
 
-    let path = config.local_storage_path();
-    match fs::create_dir_all(&path).await {
-        Err(err) if err.kind() == ErrorKind::AlreadyExists => (),
-        r => r.map_err(|e| PithosError::ServerError(Box::new(e)))?
+    if let Some(TypedHeader(headers::ContentLength(length))) = content_length && length != query.length {
+        return Err(PithosError::SizeMismatch(query.length, length));
+    }
+
+    if staging::is_stored(&config, uuid).await? {
+        return Err(PithosError::AlreadyExists);
     }
 
-    let mut file = File::create(path.join(uuid.to_string())).await
+    staging::create_staging_dir(&config).await?;
+
+    // the span covers every write to the staging file, and ends once it has been synced or abandoned
+    let write_span = info_span!("write_file", %uuid, length = query.length);
+
+    let staged_path = staging::temporary_file(&config, uuid);
+    let mut file = File::create(&staged_path).instrument(write_span.clone()).await
         .map_err(|e| PithosError::ServerError(Box::new(e)))?;
 
-    let body_with_io_error = body.map_err(|err| Error::new(ErrorKind::Other, err));
+    let expected_digest = digest.digest();
+    let mut hasher = expected_digest.as_ref().map(|digest| Hasher::new(digest.algorithm));
+
+    let body_with_io_error = body.map_err(Error::other)
+        .inspect_ok(|chunk| if let Some(hasher) = &mut hasher { hasher.update(chunk) });
     let mut body_reader = StreamReader::new(body_with_io_error);
-    tokio::io::copy_buf(&mut body_reader, &mut file).await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+
+    let result: Result<(), PithosError> = try {
+        let written = tokio::io::copy_buf(&mut (&mut body_reader).take(query.length), &mut file)
+            .instrument(write_span.clone()).await
+            .map_err(|e| PithosError::ServerError(Box::new(e)))?;
+
+        if written < query.length {
+            Err(PithosError::SizeMismatch(query.length, written))?;
+        }
+
+        if body_reader.read(&mut [0]).await.map_err(|e| PithosError::ServerError(Box::new(e)))? != 0 {
+            Err(PithosError::ExceedsDeclaredSize(query.length))?;
+        }
+
+        drop(body_reader);
+        if expected_digest.is_some() && hasher.map(Hasher::finish) != expected_digest {
+            Err(PithosError::DigestMismatch)?;
+        }
+
+        file.sync_all().instrument(write_span.clone()).await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+    };
+
+    drop(file);
+    drop(write_span);
+    if let Err(e) = result {
+        let _ = fs::remove_file(&staged_path).await;
+        return Err(e);
+    }
+
+    staging::finalise(&config, &state.metadata, &staged_path, uuid).await?;
 
     Ok(StatusCode::ACCEPTED)
 }
 
 use axum::body::StreamBody;
-use hyper::header::CONTENT_TYPE;
-use serde::Deserialize;
+use hyper::header::{ACCEPT_RANGES, AUTHORIZATION, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LOCATION, RANGE, RETRY_AFTER, WWW_AUTHENTICATE};
+use serde::{Deserialize, Serialize};
 use tokio_util::io::ReaderStream;
 use tokio::fs::File;
-use tokio::io::AsyncSeekExt;
+use tokio::io::AsyncReadExt;
 
 /// Handles requests to download a file from the local Pithos storage.
+///
+/// `HEAD` requests are answered with the same headers, without sending the file or counting as a download.
+/// Responses carry an `ETag` and a `Last-Modified` header, which clients can revalidate their copies with.
 #[axum::debug_handler]
 async fn signed_download_handler(
     State(state): State<&'static AppState>,
-    _: SignedUrl,
+    _: UnexpiredSignedUrl,
+    method: Method,
     Path(uuid): Path<Uuid>,
     Query(options): Query<DownloadQuery>,
-    perhaps_range: Option<TypedHeader<headers::Range>>
-) -> Result<(StatusCode, HeaderMap, StreamBody<impl Stream<Item = Result<bytes::Bytes, Error>>>), PithosError> {
-    let AppState { config, .. } = state;
+    perhaps_range: Option<TypedHeader<headers::Range>>,
+    request_headers: HeaderMap,
+) -> Result<Response, PithosError> {
+    let AppState { metadata, .. } = state;
+    let config = state.config();
+
+    let record = metadata.get(uuid).await?;
+    if record.as_ref().is_some_and(metadata::ObjectRecord::is_gone) {
+        return Err(PithosError::Gone);
+    }
+
+    let is_limited = record.as_ref().is_some_and(|record| record.downloads_remaining.is_some());
 
     let path = config.local_storage_path();
 
-    let mut file = File::open(path.join(uuid.to_string())).await
+    let open_span = info_span!("open_file", %uuid);
+    let file = File::open(path.join(uuid.to_string())).instrument(open_span.clone()).await
         .map_err(|e| match e.kind() {
             ErrorKind::NotFound => PithosError::NoSuchFile,
             _ => PithosError::ServerError(Box::new(e))
         })?;
 
-    let total_file_size = file.metadata().await.map_err(|e| PithosError::ServerError(Box::new(e)))?.len();
+    let file_metadata = file.metadata().instrument(open_span).await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+    let total_file_size = file_metadata.len();
 
-    let perhaps_bounds = 'bounds: {
-        let Some(TypedHeader(range_spec)) = perhaps_range else { break 'bounds None };
-        let Some((start, end)) = range_spec.iter().next() else { break 'bounds None };
-        if range_spec.iter().count() > 1 { break 'bounds None };
+    let mut headers = HeaderMap::new();
 
-        let first_byte = match start {
-            Bound::Included(index) => index,
-            Bound::Excluded(index) => index.saturating_add(1),
-            Bound::Unbounded => 0
-        };
+    let validators = Validators::of(&file_metadata)?;
+    validators.insert_into(&mut headers);
 
-        let last_byte = match end {
-            Bound::Included(index) => index,
-            Bound::Excluded(index) => index.saturating_sub(1),
-            Bound::Unbounded => total_file_size.saturating_sub(1)
-        };
+    if let Some(digest) = record.and_then(|record| record.digest) {
+        headers.insert(REPR_DIGEST, HeaderValue::try_from(digest.to_string()).map_err(|e| PithosError::ServerError(Box::new(e)))?);
+        headers.insert(DIGEST, HeaderValue::try_from(digest.legacy()).map_err(|e| PithosError::ServerError(Box::new(e)))?);
+    }
 
-        if first_byte >= total_file_size || last_byte >= total_file_size { break 'bounds None; }
+    if validators.is_unmodified_for(&request_headers) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
 
-        Some((first_byte, last_byte))
+    // a range of an outdated copy is useless to the client, so the whole file is sent instead
+    let perhaps_ranges = match perhaps_range {
+        Some(TypedHeader(range_spec)) if validators.allows_ranges(&request_headers) => {
+            ranges::resolve(&range_spec, total_file_size, config.range_limits())?
+        }
+        _ => None,
     };
 
-    let reader_stream = 'stream: {
-        if let Some((first_byte, last_byte)) = perhaps_bounds {
-            let range_size = last_byte.saturating_sub(first_byte.saturating_sub(1));
-            file.seek(SeekFrom::Start(first_byte)).await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
-            break 'stream ReaderStream::new(file).take(range_size.try_into().map_err(|e| PithosError::ServerError(Box::new(e)))?);
+    let (size, reader_stream) = match perhaps_ranges.as_deref() {
+        None => (total_file_size, ReaderStream::new(file.take(total_file_size)).boxed()),
+        Some(&[range]) => {
+            let content_range = headers::ContentRange::bytes(RangeInclusive::new(range.0, range.1), total_file_size).map_err(|e| PithosError::ServerError(Box::new(e)))?;
+            headers.typed_insert(content_range);
+            (ranges::length(range), ranges::stream_range(file, range).boxed())
+        }
+        Some(ranges) => {
+            let part_type = options.type_hint.as_ref().map_or_else(|| mime::APPLICATION_OCTET_STREAM.to_string(), ToString::to_string);
+            let multipart = Multipart::new(ranges, total_file_size, &part_type);
+            headers.insert(CONTENT_TYPE, HeaderValue::try_from(format!("multipart/byteranges; boundary={}", multipart.boundary)).map_err(|e| PithosError::ServerError(Box::new(e)))?);
+            (multipart.content_length(ranges), multipart.stream(file, ranges).await.map_err(|e| PithosError::ServerError(Box::new(e)))?.boxed())
         }
-
-        ReaderStream::new(file).take(total_file_size.try_into().map_err(|e| PithosError::ServerError(Box::new(e)))?)
     };
 
-    let body = StreamBody::new(reader_stream);
+    // for files with a download limit, the bytes sent are recorded once the body has been sent or dropped,
+    // and count as a download once they cover the file
+    let mut delivery = (method != Method::HEAD && is_limited).then(|| Delivery {
+        state, uuid, total_file_size, size,
+        ranges: perhaps_ranges.clone(),
+        sent: 0,
+    });
+    // the span lasts until the body has been sent or dropped, as the file is read while it is being sent
+    let read_span = info_span!("read_file", %uuid, size);
+    let reader_stream = reader_stream.inspect(move |chunk| {
+        let _reading = read_span.enter();
+        if let (Some(delivery), Ok(chunk)) = (&mut delivery, chunk) {
+            delivery.sent += chunk.len() as u64;
+        }
+    });
 
-    let size = perhaps_bounds
-        .map(|(first_byte, last_byte)| last_byte.saturating_sub(first_byte.saturating_sub(1)))
-        .unwrap_or(total_file_size);
+    let body = StreamBody::new(reader_stream);
 
-    let mut headers = HeaderMap::new();
     headers.insert("Content-Length", HeaderValue::from(size));
-    if let Some((first_byte, last_byte)) = perhaps_bounds {
-        let content_range = headers::ContentRange::bytes(RangeInclusive::new(first_byte, last_byte), total_file_size).map_err(|e| PithosError::ServerError(Box::new(e)))?;
-        headers.typed_insert(content_range);
-    }
-
-    if let Some(hint) = options.type_hint {
-        if let Ok(value) = HeaderValue::try_from(hint.to_string()) {
-            headers.insert("Content-Type", value);
-            headers.insert("Content-Disposition", HeaderValue::from_static("inline"));
-        }
+    headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+
+    // a multipart body has a content type of its own, which the hint only applies to the parts of
+    let is_multipart = perhaps_ranges.as_ref().is_some_and(|ranges| ranges.len() > 1);
+    if let Some(hint) = options.type_hint && !is_multipart
+        && let Ok(value) = HeaderValue::try_from(hint.to_string()) {
+        headers.insert("Content-Type", value);
+        headers.insert("Content-Disposition", HeaderValue::from_static("inline"));
     }
 
     if let Some(ext_hint) = options.ext_hint {
@@ -290,5 +820,5 @@ async fn signed_download_handler(
         }
     }
 
-    Ok((if perhaps_bounds.is_some() { StatusCode::PARTIAL_CONTENT } else { StatusCode::OK }, headers, body))
+    Ok((if perhaps_ranges.is_some() { StatusCode::PARTIAL_CONTENT } else { StatusCode::OK }, headers, body).into_response())
 }
@@ -0,0 +1,245 @@
+//! Contains the metadata store, which records every object that Pithos issues upload URLs for.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::digests::ContentDigest;
+use crate::errors::PithosError;
+use crate::service::ObjectStat;
+
+/// The schema migrations of the metadata store, in order. The schema version is tracked with `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE objects (
+        uuid TEXT PRIMARY KEY NOT NULL,
+        size INTEGER NOT NULL,
+        uploader_ip TEXT,
+        issued_at INTEGER NOT NULL,
+        completed_at INTEGER
+    );",
+    "ALTER TABLE objects ADD COLUMN expires_at INTEGER;
+    ALTER TABLE objects ADD COLUMN deleted_at INTEGER;
+    CREATE INDEX objects_by_expiry ON objects (expires_at) WHERE deleted_at IS NULL;",
+    "ALTER TABLE objects ADD COLUMN downloads_remaining INTEGER;",
+    "ALTER TABLE objects ADD COLUMN deletion_token_hash TEXT;",
+    "ALTER TABLE objects ADD COLUMN digest TEXT;",
+    "CREATE INDEX objects_by_issue ON objects (issued_at) WHERE completed_at IS NULL AND deleted_at IS NULL;",
+    "ALTER TABLE objects ADD COLUMN upload_expires_at INTEGER;
+    DROP INDEX objects_by_issue;
+    CREATE INDEX objects_by_upload_expiry ON objects (COALESCE(upload_expires_at, issued_at)) WHERE completed_at IS NULL AND deleted_at IS NULL;",
+];
+
+/// The metadata of an object that an upload URL is being issued for.
+pub struct NewObject {
+    /// The UUID of the object.
+    pub uuid: Uuid,
+    /// The declared size of the object, in bytes.
+    pub size: u64,
+    /// The IP address of the client that requested the upload URL.
+    pub uploader_ip: IpAddr,
+    /// The UNIX timestamp, in seconds, after which the object is deleted, if it has a time-to-live.
+    pub expires_at: Option<u64>,
+    /// The UNIX timestamp, in seconds, after which the object's upload URL can no longer be used.
+    pub upload_expires_at: u64,
+    /// How many times the object may be downloaded, if the number of downloads is limited.
+    pub max_downloads: Option<u64>,
+    /// The hash of the secret with which the uploader can delete the object.
+    pub deletion_token_hash: String,
+    /// The digest that the uploader declared for the object, if any.
+    pub digest: Option<ContentDigest>,
+}
+
+/// The recorded metadata of a single object.
+pub struct ObjectRecord {
+    /// The UNIX timestamp, in seconds, at which the upload was completed, if it has been.
+    pub completed_at: Option<u64>,
+    /// The UNIX timestamp, in seconds, after which the object is deleted, if it has a time-to-live.
+    pub expires_at: Option<u64>,
+    /// The UNIX timestamp, in seconds, at which the object was deleted, if it has been.
+    pub deleted_at: Option<u64>,
+    /// How many more times the object may be downloaded, if the number of downloads is limited.
+    pub downloads_remaining: Option<u64>,
+    /// The hash of the secret with which the uploader can delete the object, if one was issued.
+    pub deletion_token_hash: Option<String>,
+    /// The digest that the uploader declared for the object, if any.
+    pub digest: Option<ContentDigest>,
+}
+
+impl ObjectRecord {
+    /// Returns whether the object has been uploaded in full.
+    pub const fn is_complete(&self) -> bool {
+        self.completed_at.is_some()
+    }
+
+    /// Returns whether the object has expired, been deleted, or been downloaded as many times as allowed,
+    /// even if it has yet to be removed from the storage.
+    pub fn is_gone(&self) -> bool {
+        self.deleted_at.is_some()
+            || self.expires_at.is_some_and(|expiry| expiry <= now())
+            || self.downloads_remaining == Some(0)
+    }
+}
+
+/// Returns the current UNIX timestamp in seconds.
+pub fn now() -> u64 {
+    timestamp(SystemTime::now())
+}
+
+/// Converts a point in time to a UNIX timestamp in seconds.
+pub fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+/// An embedded `SQLite` database recording the objects stored by Pithos.
+#[derive(Clone)]
+pub struct MetadataStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl MetadataStore {
+    /// Opens the metadata store at the given path, creating and migrating it as necessary.
+    pub fn open(path: &Path) -> Result<Self, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+
+        let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            connection.execute_batch(migration)?;
+            connection.pragma_update(None, "user_version", index + 1)?;
+        }
+
+        Ok(Self { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    /// Runs the given query against the database on the blocking thread pool.
+    async fn with_connection<T: Send + 'static>(&self, query: impl FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static) -> Result<T, PithosError> {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || query(&connection.lock().unwrap_or_else(PoisonError::into_inner))).await
+            .map_err(|e| PithosError::ServerError(Box::new(e)))?
+            .map_err(PithosError::from)
+    }
+
+    /// Checks that the database can be queried.
+    pub async fn check(&self) -> Result<(), PithosError> {
+        self.with_connection(|connection| connection.query_row("SELECT 1", [], |_| Ok(()))).await
+    }
+
+    /// Records that an upload URL was issued for a new object.
+    pub async fn record_issued(&self, object: NewObject) -> Result<(), PithosError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO objects (uuid, size, uploader_ip, issued_at, expires_at, upload_expires_at, downloads_remaining, deletion_token_hash, digest)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    object.uuid.to_string(), object.size, object.uploader_ip.to_string(), now(),
+                    object.expires_at, object.upload_expires_at, object.max_downloads, object.deletion_token_hash,
+                    object.digest.as_ref().map(ToString::to_string),
+                ],
+            ).map(|_| ())
+        }).await
+    }
+
+    /// Records an object that was stored before the metadata store existed, as a completed upload without a
+    /// time-to-live, download limit or deletion token. Objects that are already recorded are left as they are.
+    pub async fn record_existing(&self, uuid: Uuid, stat: ObjectStat) -> Result<(), PithosError> {
+        self.with_connection(move |connection| {
+            let stored_at = stat.created_at.unwrap_or_else(now);
+            connection.execute(
+                "INSERT OR IGNORE INTO objects (uuid, size, issued_at, completed_at) VALUES (?1, ?2, ?3, ?3)",
+                params![uuid.to_string(), stat.size, stored_at],
+            ).map(|_| ())
+        }).await
+    }
+
+    /// Counts a download of an object, returning how many downloads remain if they are limited.
+    ///
+    /// Returns `None` if the number of downloads is unlimited, or if no downloads remained to begin with.
+    pub async fn record_download(&self, uuid: Uuid) -> Result<Option<u64>, PithosError> {
+        self.with_connection(move |connection| {
+            connection.query_row(
+                "UPDATE objects SET downloads_remaining = downloads_remaining - 1
+                    WHERE uuid = ?1 AND downloads_remaining > 0
+                    RETURNING downloads_remaining",
+                params![uuid.to_string()],
+                |row| row.get(0),
+            ).optional()
+        }).await
+    }
+
+    /// Makes an object expire at the given UNIX timestamp, unless it already expires sooner.
+    pub async fn expire_at(&self, uuid: Uuid, expires_at: u64) -> Result<(), PithosError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE objects SET expires_at = MIN(COALESCE(expires_at, ?2), ?2) WHERE uuid = ?1",
+                params![uuid.to_string(), expires_at],
+            ).map(|_| ())
+        }).await
+    }
+
+    /// Records that an object has been deleted from the storage.
+    pub async fn record_deleted(&self, uuid: Uuid) -> Result<(), PithosError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE objects SET deleted_at = ?2 WHERE uuid = ?1 AND deleted_at IS NULL",
+                params![uuid.to_string(), now()],
+            ).map(|_| ())
+        }).await
+    }
+
+    /// Returns the objects that have expired, but not yet been deleted.
+    pub async fn expired(&self) -> Result<Vec<Uuid>, PithosError> {
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare("SELECT uuid FROM objects WHERE expires_at <= ?1 AND deleted_at IS NULL")?;
+            let uuids = statement.query_map(params![now()], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(uuids.iter().filter_map(|uuid| uuid.parse().ok()).collect())
+        }).await
+    }
+
+    /// Returns the objects whose upload URLs expired before the given UNIX timestamp, but that have neither
+    /// been recorded as uploaded in full nor been deleted.
+    ///
+    /// Objects recorded before upload URL expiries were, are treated as if their URLs expired when they were issued.
+    pub async fn incomplete(&self, upload_expired_before: u64) -> Result<Vec<Uuid>, PithosError> {
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT uuid FROM objects WHERE COALESCE(upload_expires_at, issued_at) < ?1 AND completed_at IS NULL AND deleted_at IS NULL"
+            )?;
+            let uuids = statement.query_map(params![upload_expired_before], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(uuids.iter().filter_map(|uuid| uuid.parse().ok()).collect())
+        }).await
+    }
+
+    /// Records that an object has been uploaded in full.
+    pub async fn record_completed(&self, uuid: Uuid) -> Result<(), PithosError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE objects SET completed_at = ?2 WHERE uuid = ?1 AND completed_at IS NULL",
+                params![uuid.to_string(), now()],
+            ).map(|_| ())
+        }).await
+    }
+
+    /// Returns the metadata of the given object, or `None` if no upload URL was ever issued for it.
+    pub async fn get(&self, uuid: Uuid) -> Result<Option<ObjectRecord>, PithosError> {
+        self.with_connection(move |connection| {
+            connection.query_row(
+                "SELECT completed_at, expires_at, deleted_at, downloads_remaining, deletion_token_hash, digest FROM objects WHERE uuid = ?1",
+                params![uuid.to_string()],
+                |row| Ok(ObjectRecord {
+                    completed_at: row.get(0)?,
+                    expires_at: row.get(1)?,
+                    deleted_at: row.get(2)?,
+                    downloads_remaining: row.get(3)?,
+                    deletion_token_hash: row.get(4)?,
+                    digest: row.get::<_, Option<String>>(5)?.and_then(|digest| digest.parse().ok()),
+                }),
+            ).optional()
+        }).await
+    }
+}
@@ -0,0 +1,196 @@
+//! Collects Prometheus metrics about how Pithos is used, and serves them on a listener of their own.
+//!
+//! The metrics are kept off the main listener, so that they can be exposed to a private network only.
+
+use std::io;
+use std::net::SocketAddr;
+
+use axum::body::{Body, Bytes, StreamBody, boxed};
+use axum::extract::{MatchedPath, State};
+use axum::http::{header, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use futures::TryStreamExt;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::AppState;
+use crate::errors::ErrorName;
+use crate::rate_limits::into_stream;
+use crate::service::Service;
+use crate::tus::TUS_ENDPOINT;
+
+/// The metrics of the application.
+pub struct Metrics {
+    /// The registry that the metrics are exported from.
+    registry: Registry,
+    /// The number of requests handled, by route, method and status code.
+    requests: IntCounterVec,
+    /// How long requests took until their response started, by route and method.
+    request_duration: HistogramVec,
+    /// The number of bytes transferred through the signed routes, by direction.
+    transferred_bytes: IntCounterVec,
+    /// The number of transfers in progress on the signed routes, by direction.
+    active_transfers: IntGaugeVec,
+    /// The number of signed URLs issued, by service and kind.
+    signed_urls: IntCounterVec,
+    /// The number of error responses, by error.
+    errors: IntCounterVec,
+    /// The number of requests rejected by the IP filter.
+    blocked_requests: IntCounter,
+}
+
+impl Metrics {
+    /// Creates and registers the metrics.
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("pithos".to_string()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "The number of requests handled"),
+            &["route", "method", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "How long requests took until their response started"),
+            &["route", "method"],
+        )?;
+        let transferred_bytes = IntCounterVec::new(
+            Opts::new("transferred_bytes_total", "The number of bytes uploaded and downloaded through the signed routes"),
+            &["direction"],
+        )?;
+        let active_transfers = IntGaugeVec::new(
+            Opts::new("active_transfers", "The number of uploads and downloads in progress on the signed routes"),
+            &["direction"],
+        )?;
+        let signed_urls = IntCounterVec::new(
+            Opts::new("signed_urls_issued_total", "The number of signed URLs issued"),
+            &["service", "kind"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "The number of error responses"),
+            &["error"],
+        )?;
+        let blocked_requests = IntCounter::new("blocked_requests_total", "The number of requests rejected by the IP filter")?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(transferred_bytes.clone()))?;
+        registry.register(Box::new(active_transfers.clone()))?;
+        registry.register(Box::new(signed_urls.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(blocked_requests.clone()))?;
+
+        Ok(Self { registry, requests, request_duration, transferred_bytes, active_transfers, signed_urls, errors, blocked_requests })
+    }
+
+    /// Records that the given service issued a signed URL of the given kind, i.e. `upload` or `download`.
+    pub fn record_signed_url(&self, service: &dyn Service, kind: &str) {
+        self.signed_urls.with_label_values(&[&service.to_string(), kind]).inc();
+    }
+
+    /// Records that the IP filter rejected a request.
+    pub fn record_blocked(&self) {
+        self.blocked_requests.inc();
+    }
+
+    /// Encodes the metrics in the Prometheus text format.
+    fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// The direction label of uploads.
+const UPLOAD: &str = "upload";
+/// The direction label of downloads.
+const DOWNLOAD: &str = "download";
+
+/// Returns the direction of the transfer that a request makes on the signed routes, if it makes one.
+fn transfer_direction(method: &Method, route: &str) -> Option<&'static str> {
+    if route.starts_with("/signed_upload") || (route.starts_with(TUS_ENDPOINT) && method == Method::PATCH) {
+        Some(UPLOAD)
+    } else if route.starts_with("/signed_download") && method != Method::HEAD {
+        Some(DOWNLOAD)
+    } else {
+        None
+    }
+}
+
+/// Counts the bytes of a transfer, which is marked as active for as long as this is alive.
+struct Transfer {
+    /// The counter of the bytes transferred in the transfer's direction.
+    bytes: IntCounter,
+    /// The gauge of the transfers active in the transfer's direction.
+    active: IntGauge,
+}
+
+impl Transfer {
+    /// Marks a transfer in the given direction as started.
+    fn start(metrics: &Metrics, direction: &str) -> Self {
+        let active = metrics.active_transfers.with_label_values(&[direction]);
+        active.inc();
+
+        Self { bytes: metrics.transferred_bytes.with_label_values(&[direction]), active }
+    }
+
+    /// Counts the bytes of a chunk of the transfer.
+    fn count(&self, chunk: &Bytes) {
+        self.bytes.inc_by(chunk.len() as u64);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.active.dec();
+    }
+}
+
+/// Records the metrics of every request.
+///
+/// Transfers on the signed routes last until their body has been sent in full, or until it is dropped.
+pub async fn record(State(state): State<&'static AppState>, request: Request<Body>, next: Next<Body>) -> Response {
+    let metrics = &state.metrics;
+    let route = request.extensions().get::<MatchedPath>().map_or("unmatched", MatchedPath::as_str).to_string();
+    let method = request.method().clone();
+    let started_at = Instant::now();
+
+    let response = match transfer_direction(&method, &route) {
+        Some(direction @ UPLOAD) => {
+            let transfer = Transfer::start(metrics, direction);
+            next.run(request.map(|body| Body::wrap_stream(into_stream(body).inspect_ok(move |chunk| transfer.count(chunk))))).await
+        }
+        Some(direction) => {
+            let transfer = Transfer::start(metrics, direction);
+            next.run(request).await.map(|body| boxed(StreamBody::new(into_stream(body).inspect_ok(move |chunk| transfer.count(chunk)))))
+        }
+        None => next.run(request).await,
+    };
+
+    metrics.request_duration.with_label_values(&[&route, method.as_str()]).observe(started_at.elapsed().as_secs_f64());
+    metrics.requests.with_label_values(&[&route, method.as_str(), response.status().as_str()]).inc();
+
+    if let Some(ErrorName(name)) = response.extensions().get::<ErrorName>() {
+        metrics.errors.with_label_values(&[name]).inc();
+    }
+
+    response
+}
+
+/// Serves the metrics in the Prometheus text format.
+async fn metrics_handler(State(state): State<&'static AppState>) -> Response {
+    match state.metrics.encode() {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Serves `/metrics` on the given address.
+pub async fn serve(state: &'static AppState, addr: SocketAddr) -> io::Result<()> {
+    let app = Router::new().route("/metrics", get(metrics_handler)).with_state(state);
+
+    info!("Serving metrics on {addr}");
+    axum_server::bind(addr).serve(app.into_make_service()).await
+}
@@ -0,0 +1,290 @@
+//! Resolves the byte ranges requested from the local Pithos storage, and streams them to the client.
+//!
+//! A single range is sent as is, while several ranges are sent as a `multipart/byteranges` body,
+//! as described in <https://www.rfc-editor.org/rfc/rfc9110#name-media-type-multipart-byteran>.
+
+use std::collections::{Bound, HashMap};
+use std::io::{Error, SeekFrom};
+use std::sync::{Mutex, PoisonError};
+
+use axum::headers;
+use bytes::Bytes;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::config::RangeLimits;
+use crate::errors::PithosError;
+
+/// An inclusive range of bytes in a file, as (first byte, last byte).
+pub type ByteRange = (u64, u64);
+
+/// Resolves the ranges requested in a `Range` header against a file of the given size.
+///
+/// As per RFC 9110, ranges that start past the end of the file are skipped, ranges that end past it are
+/// shortened, and suffix ranges such as `bytes=-500` select the last bytes of the file.
+///
+/// Returns `None` if the header is malformed and should be ignored, so that the whole file is sent instead.
+/// Fails with [`PithosError::InvalidRange`] if none of the ranges can be satisfied, and with
+/// [`PithosError::ExcessiveRanges`] if the ranges exceed the given limits.
+pub fn resolve(range_spec: &headers::Range, total_file_size: u64, limits: RangeLimits) -> Result<Option<Vec<ByteRange>>, PithosError> {
+    let mut ranges = Vec::new();
+    let mut unsatisfiable = None;
+
+    for bounds in range_spec.iter() {
+        let (first_byte, last_byte) = match bounds {
+            (Bound::Included(first_byte), Bound::Included(last_byte)) if first_byte <= last_byte => (first_byte, last_byte),
+            (Bound::Included(first_byte), Bound::Unbounded) => (first_byte, total_file_size.saturating_sub(1)),
+            (Bound::Unbounded, Bound::Included(suffix_length)) => (total_file_size.saturating_sub(suffix_length), total_file_size.saturating_sub(1)),
+            _ => return Ok(None),
+        };
+
+        let is_empty_suffix = bounds.0 == Bound::Unbounded && bounds.1 == Bound::Included(0);
+        if first_byte >= total_file_size || is_empty_suffix {
+            unsatisfiable.get_or_insert((first_byte, last_byte));
+            continue;
+        }
+
+        ranges.push((first_byte, last_byte.min(total_file_size - 1)));
+    }
+
+    if ranges.is_empty() {
+        return match unsatisfiable {
+            Some((first_byte, last_byte)) => Err(PithosError::InvalidRange(first_byte, last_byte, total_file_size)),
+            None => Ok(None),
+        };
+    }
+
+    if ranges.len() > limits.max_ranges || overlap(&ranges) > limits.max_overlapping_ranges {
+        return Err(PithosError::ExcessiveRanges);
+    }
+
+    Ok(Some(ranges))
+}
+
+/// Returns the largest number of the given ranges that contain the same byte.
+fn overlap(ranges: &[ByteRange]) -> usize {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_unstable();
+
+    // the last bytes of the ranges that contain the current first byte, in no particular order
+    let mut open: Vec<u64> = Vec::new();
+    let mut deepest = 0;
+
+    for (first_byte, last_byte) in sorted {
+        open.retain(|&open_last_byte| open_last_byte >= first_byte);
+        open.push(last_byte);
+        deepest = deepest.max(open.len());
+    }
+
+    deepest
+}
+
+/// Returns the number of bytes in a range.
+pub const fn length((first_byte, last_byte): ByteRange) -> u64 {
+    last_byte - first_byte + 1
+}
+
+/// The largest number of disjoint ranges that are kept of a file's coverage.
+const MAX_COVERED_RANGES: usize = 64;
+
+/// The bytes of each file with a download limit that have been sent to clients since it was last counted as downloaded.
+///
+/// Downloads may be split across several range requests, such as when a client resumes an interrupted transfer,
+/// so a download is only counted once the bytes sent cover the whole file, however many requests that took.
+#[derive(Default)]
+pub struct Coverage {
+    /// The sorted, disjoint ranges of each file that have been sent.
+    files: Mutex<HashMap<Uuid, Vec<ByteRange>>>,
+}
+
+impl Coverage {
+    /// Records that the given ranges of a file of the given size have been sent, and returns whether the bytes sent
+    /// now cover the whole file. Once they do, the file's coverage starts over, so that the next download is counted anew.
+    ///
+    /// Empty files are covered by any transfer. At most [`MAX_COVERED_RANGES`] ranges are kept of each file, beyond
+    /// which the closest ranges are merged, so that scattered requests count as a download early rather than never.
+    pub fn record(&self, uuid: Uuid, sent: &[ByteRange], total_file_size: u64) -> bool {
+        if total_file_size == 0 {
+            return true;
+        }
+
+        if sent.is_empty() {
+            return false;
+        }
+
+        let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        let covered = files.entry(uuid).or_default();
+        for &range in sent {
+            insert(covered, range);
+        }
+
+        while covered.len() > MAX_COVERED_RANGES {
+            let Some(index) = (1..covered.len()).min_by_key(|&index| covered[index].0 - covered[index - 1].1) else { break };
+            covered[index - 1].1 = covered[index].1;
+            covered.remove(index);
+        }
+
+        let is_covered = covered.as_slice() == [(0, total_file_size - 1)];
+        if is_covered {
+            files.remove(&uuid);
+        }
+        drop(files);
+
+        is_covered
+    }
+
+    /// Forgets which bytes of a file have been sent, such as once it has been deleted.
+    pub fn forget(&self, uuid: Uuid) {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner).remove(&uuid);
+    }
+}
+
+/// Adds a range to sorted, disjoint ranges, merging it with the ranges that it overlaps or adjoins.
+fn insert(covered: &mut Vec<ByteRange>, (mut first_byte, mut last_byte): ByteRange) {
+    // the ranges that end more than a byte before this one starts are left as they are
+    let start = covered.partition_point(|&(_, covered_last_byte)| covered_last_byte.saturating_add(1) < first_byte);
+
+    let mut end = start;
+    while let Some(&(covered_first_byte, covered_last_byte)) = covered.get(end) && covered_first_byte <= last_byte.saturating_add(1) {
+        first_byte = first_byte.min(covered_first_byte);
+        last_byte = last_byte.max(covered_last_byte);
+        end += 1;
+    }
+
+    covered.splice(start..end, [(first_byte, last_byte)]);
+}
+
+/// The body of a response containing several ranges of a file.
+pub struct Multipart {
+    /// The string that delimits the parts of the body.
+    pub boundary: String,
+    /// The headers of each part, with the delimiter before them, in the same order as the ranges.
+    part_headers: Vec<Bytes>,
+    /// The delimiter that ends the body.
+    epilogue: Bytes,
+}
+
+impl Multipart {
+    /// Lays out a `multipart/byteranges` body for the given ranges of a file, whose parts have the given content type.
+    pub fn new(ranges: &[ByteRange], total_file_size: u64, content_type: &str) -> Self {
+        let boundary = uuid::Uuid::new_v4().simple().to_string();
+
+        let part_headers = ranges.iter().map(|(first_byte, last_byte)| Bytes::from(format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {first_byte}-{last_byte}/{total_file_size}\r\n\r\n"
+        ))).collect();
+
+        let epilogue = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+
+        Self { boundary, part_headers, epilogue }
+    }
+
+    /// Returns the length of the body in bytes, including the given ranges of the file.
+    pub fn content_length(&self, ranges: &[ByteRange]) -> u64 {
+        let framing = self.part_headers.iter().chain([&self.epilogue]).map(|bytes| bytes.len() as u64).sum::<u64>();
+        framing + ranges.iter().copied().map(length).sum::<u64>()
+    }
+
+    /// Streams the body, reading the given ranges from the file.
+    pub async fn stream(self, file: File, ranges: &[ByteRange]) -> Result<impl Stream<Item = Result<Bytes, Error>> + use<>, Error> {
+        let mut parts = Vec::with_capacity(ranges.len());
+        for (headers, &range) in self.part_headers.into_iter().zip(ranges) {
+            parts.push(stream::once(future::ready(Ok(headers))).chain(stream_range(file.try_clone().await?, range)));
+        }
+
+        Ok(stream::iter(parts).flatten().chain(stream::once(future::ready(Ok(self.epilogue)))))
+    }
+}
+
+/// Streams a single range of a file.
+pub fn stream_range(mut file: File, range @ (first_byte, _): ByteRange) -> impl Stream<Item = Result<Bytes, Error>> {
+    // the parts of a multipart body share the file's cursor, so each part only seeks once it is reached
+    stream::once(async move {
+        file.seek(SeekFrom::Start(first_byte)).await?;
+        Ok::<_, Error>(ReaderStream::new(file.take(length(range))))
+    }).try_flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::headers::{Header, HeaderValue};
+
+    use super::*;
+
+    /// Limits loose enough not to reject any of the ranges under test.
+    const NO_LIMITS: RangeLimits = RangeLimits { max_ranges: usize::MAX, max_overlapping_ranges: usize::MAX };
+
+    /// Resolves the ranges of a `Range` header value against a file of the given size.
+    fn resolve_header(value: &'static str, total_file_size: u64, limits: RangeLimits) -> Result<Option<Vec<ByteRange>>, PithosError> {
+        let range_spec = headers::Range::decode(&mut std::iter::once(&HeaderValue::from_static(value))).unwrap();
+        resolve(&range_spec, total_file_size, limits)
+    }
+
+    #[test]
+    fn closed_and_open_ended_ranges() {
+        assert_eq!(resolve_header("bytes=0-499", 1000, NO_LIMITS).unwrap(), Some(vec![(0, 499)]));
+        assert_eq!(resolve_header("bytes=500-", 1000, NO_LIMITS).unwrap(), Some(vec![(500, 999)]));
+        assert_eq!(resolve_header("bytes=999-999", 1000, NO_LIMITS).unwrap(), Some(vec![(999, 999)]));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(resolve_header("bytes=-500", 1000, NO_LIMITS).unwrap(), Some(vec![(500, 999)]));
+        // a suffix longer than the file selects all of it
+        assert_eq!(resolve_header("bytes=-5000", 1000, NO_LIMITS).unwrap(), Some(vec![(0, 999)]));
+    }
+
+    #[test]
+    fn ranges_past_the_end() {
+        assert_eq!(resolve_header("bytes=900-1999", 1000, NO_LIMITS).unwrap(), Some(vec![(900, 999)]));
+        assert_eq!(resolve_header("bytes=0-9,1000-1099", 1000, NO_LIMITS).unwrap(), Some(vec![(0, 9)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert!(matches!(resolve_header("bytes=1000-1099", 1000, NO_LIMITS), Err(PithosError::InvalidRange(1000, 1099, 1000))));
+        assert!(matches!(resolve_header("bytes=1000-", 1000, NO_LIMITS), Err(PithosError::InvalidRange(1000, _, 1000))));
+        assert!(matches!(resolve_header("bytes=-0", 1000, NO_LIMITS), Err(PithosError::InvalidRange(_, _, 1000))));
+        // every range of an empty file is unsatisfiable
+        assert!(matches!(resolve_header("bytes=0-", 0, NO_LIMITS), Err(PithosError::InvalidRange(_, _, 0))));
+        assert!(matches!(resolve_header("bytes=-500", 0, NO_LIMITS), Err(PithosError::InvalidRange(_, _, 0))));
+    }
+
+    #[test]
+    fn malformed_ranges_are_ignored() {
+        assert!(resolve_header("bytes=500-100", 1000, NO_LIMITS).unwrap().is_none());
+        assert!(resolve_header("bytes=0-9,500-100", 1000, NO_LIMITS).unwrap().is_none());
+    }
+
+    #[test]
+    fn too_many_ranges() {
+        let limits = RangeLimits { max_ranges: 2, max_overlapping_ranges: usize::MAX };
+        assert_eq!(resolve_header("bytes=0-9,20-29", 1000, limits).unwrap(), Some(vec![(0, 9), (20, 29)]));
+        assert!(matches!(resolve_header("bytes=0-9,20-29,40-49", 1000, limits), Err(PithosError::ExcessiveRanges)));
+        // skipped ranges don't count towards the limit
+        assert!(resolve_header("bytes=0-9,20-29,2000-2009", 1000, limits).is_ok());
+    }
+
+    #[test]
+    fn too_many_overlapping_ranges() {
+        let limits = RangeLimits { max_ranges: usize::MAX, max_overlapping_ranges: 2 };
+        assert!(resolve_header("bytes=0-99,50-149,100-199", 1000, limits).is_ok());
+        assert!(matches!(resolve_header("bytes=0-99,50-149,99-199", 1000, limits), Err(PithosError::ExcessiveRanges)));
+        // suffix and open-ended ranges overlap the ranges they end or start in
+        assert!(resolve_header("bytes=0-,500-599,-100", 1000, limits).is_ok());
+        assert!(matches!(resolve_header("bytes=0-,-600,500-599", 1000, limits), Err(PithosError::ExcessiveRanges)));
+    }
+
+    #[test]
+    fn overlap_depth() {
+        assert_eq!(overlap(&[]), 0);
+        assert_eq!(overlap(&[(0, 9)]), 1);
+        assert_eq!(overlap(&[(0, 9), (10, 19)]), 1);
+        // ranges that share only their last and first bytes still overlap
+        assert_eq!(overlap(&[(0, 9), (9, 19)]), 2);
+        assert_eq!(overlap(&[(20, 29), (0, 99), (10, 19), (15, 25)]), 3);
+        assert_eq!(overlap(&[(0, 9), (0, 9), (0, 9)]), 3);
+    }
+}
@@ -0,0 +1,251 @@
+//! Limits how quickly clients may request URLs and transfer files, using token buckets.
+//!
+//! Every limiter has a bucket for each client IP address, as well as a global bucket shared by all clients.
+//! Requests for upload and download URLs take one token each, while transfers on the signed routes take one
+//! token per byte. Transfers may overdraw the buckets, in which case they are slowed down until the buckets
+//! refill, and further transfers are refused until then.
+
+use core::time::Duration;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+
+use axum::body::{Body, Bytes, HttpBody, StreamBody, boxed};
+use axum::extract::State;
+use axum::http::{Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_client_ip::SecureClientIp;
+use futures::{Stream, StreamExt, stream};
+use tokio::time::Instant;
+use tracing::instrument;
+
+use crate::AppState;
+use crate::config::{BucketOptions, RateLimit};
+use crate::errors::PithosError;
+
+/// The number of per-IP buckets at which full buckets are first forgotten, so that the limiter's memory use stays bounded.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// A bucket of tokens that refills at a constant rate, up to its capacity.
+struct TokenBucket {
+    /// The number of tokens in the bucket, which is negative if the bucket has been overdrawn.
+    tokens: f64,
+    /// When the bucket was last refilled.
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    fn new(options: BucketOptions) -> Self {
+        Self { tokens: options.burst, refilled_at: Instant::now() }
+    }
+
+    /// Adds the tokens that have accumulated since the bucket was last refilled.
+    fn refill(&mut self, options: BucketOptions) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = options.rate.mul_add(elapsed, self.tokens).min(options.burst);
+        self.refilled_at = now;
+    }
+
+    /// Returns how long it takes for the bucket to hold the given number of tokens.
+    fn time_until(&self, tokens: f64, options: BucketOptions) -> Duration {
+        Duration::try_from_secs_f64((tokens - self.tokens).max(0.0) / options.rate).unwrap_or(Duration::MAX)
+    }
+}
+
+/// The buckets of individual clients.
+struct ClientBuckets {
+    /// The bucket of each client that has been seen since its bucket was last full.
+    buckets: HashMap<IpAddr, TokenBucket>,
+    /// The number of buckets at which full buckets are next forgotten.
+    prune_at: usize,
+}
+
+impl ClientBuckets {
+    /// Returns the client's bucket, refilled, first forgetting full buckets if there are many.
+    ///
+    /// Full buckets are only looked for once the number of buckets has doubled since they last were, so that
+    /// pruning takes amortised constant time even when most clients are being limited.
+    fn get(&mut self, ip: IpAddr, options: BucketOptions) -> &mut TokenBucket {
+        if self.buckets.len() >= self.prune_at {
+            self.buckets.retain(|_, bucket| {
+                bucket.refill(options);
+                bucket.tokens < options.burst
+            });
+            self.prune_at = PRUNE_THRESHOLD.max(self.buckets.len() * 2);
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert_with(|| TokenBucket::new(options));
+        bucket.refill(options);
+        bucket
+    }
+}
+
+/// A pair of per-IP and global token buckets.
+pub struct RateLimiter {
+    /// The limits that the buckets enforce, which change when the configuration is reloaded.
+    limit: Mutex<RateLimit>,
+    /// The bucket shared by all clients, if they are limited as a whole.
+    global: Mutex<Option<TokenBucket>>,
+    /// The buckets of individual clients.
+    per_ip: Mutex<ClientBuckets>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter whose buckets start out full.
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit: Mutex::new(limit),
+            global: Mutex::new(None),
+            per_ip: Mutex::new(ClientBuckets { buckets: HashMap::new(), prune_at: PRUNE_THRESHOLD }),
+        }
+    }
+
+    /// Replaces the limits that the buckets enforce.
+    ///
+    /// The tokens in existing buckets are kept, so that clients can't reset their limits by waiting for a reload,
+    /// but buckets are capped to their new capacities as soon as they are next refilled.
+    pub fn reconfigure(&self, limit: RateLimit) {
+        *self.limit.lock().unwrap_or_else(PoisonError::into_inner) = limit;
+    }
+
+    /// Returns the limits that the buckets currently enforce.
+    fn limit(&self) -> RateLimit {
+        *self.limit.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Applies the given function to the client's bucket and the global bucket, along with their limits, if they are limited.
+    ///
+    /// Both buckets stay locked while the function runs, always in the same order, so that checking them and
+    /// taking tokens from them happen as one step.
+    fn with_buckets<T>(&self, ip: IpAddr, apply: impl FnOnce(&mut [(&mut TokenBucket, BucketOptions)]) -> T) -> T {
+        let limit = self.limit();
+        let mut per_ip = limit.per_ip.map(|options| (self.per_ip.lock().unwrap_or_else(PoisonError::into_inner), options));
+        let mut global = limit.global.map(|options| (self.global.lock().unwrap_or_else(PoisonError::into_inner), options));
+
+        let mut buckets = Vec::with_capacity(2);
+        if let Some((per_ip, options)) = &mut per_ip {
+            buckets.push((per_ip.get(ip, *options), *options));
+        }
+
+        if let Some((global, options)) = &mut global {
+            let bucket = global.get_or_insert_with(|| TokenBucket::new(*options));
+            bucket.refill(*options);
+            buckets.push((bucket, *options));
+        }
+
+        apply(&mut buckets)
+    }
+
+    /// Takes the given number of tokens from the client's buckets, or fails with how long the client must wait to have them.
+    #[instrument(skip(self))]
+    pub fn acquire(&self, ip: IpAddr, tokens: f64) -> Result<(), Duration> {
+        self.with_buckets(ip, |buckets| {
+            let wait = longest_wait(buckets, tokens);
+            if !wait.is_zero() {
+                return Err(wait);
+            }
+
+            for (bucket, _) in buckets {
+                bucket.tokens -= tokens;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Fails with how long the client must wait if its buckets have been overdrawn.
+    #[instrument(skip(self))]
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let wait = self.with_buckets(ip, |buckets| longest_wait(buckets, 0.0));
+
+        if wait.is_zero() { Ok(()) } else { Err(wait) }
+    }
+
+    /// Takes the given number of tokens from the client's buckets, overdrawing them if necessary,
+    /// and waits until they would have held that many tokens.
+    pub async fn consume(&self, ip: IpAddr, tokens: f64) {
+        let wait = self.with_buckets(ip, |buckets| {
+            for (bucket, _) in buckets.iter_mut() {
+                bucket.tokens -= tokens;
+            }
+
+            longest_wait(buckets, 0.0)
+        });
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Returns whether the limiter has any buckets at all.
+    fn is_limited(&self) -> bool {
+        let limit = self.limit();
+        limit.per_ip.is_some() || limit.global.is_some()
+    }
+
+    /// Slows down a stream of bytes to the rate allowed for the client.
+    fn throttle<E>(&'static self, ip: IpAddr, bytes: impl Stream<Item = Result<Bytes, E>>) -> impl Stream<Item = Result<Bytes, E>> {
+        bytes.then(move |chunk| async move {
+            if let Ok(chunk) = &chunk {
+                #[allow(clippy::cast_precision_loss)]
+                self.consume(ip, chunk.len() as f64).await;
+            }
+            chunk
+        })
+    }
+}
+
+/// Returns how long it takes for all of the given buckets to hold the given number of tokens.
+fn longest_wait(buckets: &[(&mut TokenBucket, BucketOptions)], tokens: f64) -> Duration {
+    buckets.iter().map(|(bucket, options)| bucket.time_until(tokens, *options)).max().unwrap_or_default()
+}
+
+/// The rate limiters of the application.
+pub struct RateLimiters {
+    /// Limits requests for upload and download URLs.
+    pub requests: RateLimiter,
+    /// Limits the bytes transferred on the signed routes.
+    pub bytes: RateLimiter,
+}
+
+/// Turns a body into a stream of its data.
+pub fn into_stream<B: HttpBody + Unpin>(body: B) -> impl Stream<Item = Result<Bytes, B::Error>> where B::Data: Into<Bytes> {
+    stream::unfold(body, |mut body| async move {
+        body.data().await.map(|data| (data.map(Into::into), body))
+    })
+}
+
+/// Refuses requests from clients that have exceeded their rate limits, and slows down their transfers on the signed routes.
+pub async fn limit_rates(
+    State(state): State<&'static AppState>,
+    SecureClientIp(ip): SecureClientIp,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, PithosError> {
+    // preflights carry no credentials and transfer nothing, so they don't count against the client
+    if request.method() == Method::OPTIONS {
+        return Ok(next.run(request).await);
+    }
+
+    let limiters = &state.rate_limiters;
+    let path = request.uri().path();
+
+    if path == "/upload" || path.starts_with("/download/") {
+        limiters.requests.acquire(ip, 1.0).map_err(PithosError::RateLimited)?;
+        return Ok(next.run(request).await);
+    }
+
+    if !path.starts_with("/signed_") || !limiters.bytes.is_limited() {
+        return Ok(next.run(request).await);
+    }
+
+    limiters.bytes.check(ip).map_err(PithosError::RateLimited)?;
+
+    let request = request.map(|body| Body::wrap_stream(limiters.bytes.throttle(ip, into_stream(body))));
+    let response = next.run(request).await;
+
+    Ok(response.map(|body| boxed(StreamBody::new(limiters.bytes.throttle(ip, into_stream(body))))))
+}
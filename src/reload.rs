@@ -0,0 +1,151 @@
+//! Reloads the configuration file while the server is running, whenever it or the API key file changes, or the
+//! process receives `SIGHUP`.
+//!
+//! A new configuration only replaces the current one once it has been parsed in full, and requests that are
+//! already being handled keep the configuration they started with.
+
+use core::time::Duration;
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::fs;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{error, info};
+
+use crate::{AppState, CONFIG_PATH, initialise_config};
+
+/// How often the configuration file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The tables and keys of the configuration that are only read at startup, and so can't be changed by a reload.
+const RESTART_REQUIRED: &[&str] = &["local_storage_path", "metadata_path", "service", "services", "server"];
+
+/// Reloads the configuration whenever it changes, for the lifetime of the program.
+///
+/// `table` is the TOML table that the current configuration was parsed from.
+pub async fn watch(state: &'static AppState, mut table: toml::Table) {
+    let mut hangups = Hangups::new();
+    let mut ticker = interval(POLL_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut modified = modification_time(state).await.ok();
+
+    loop {
+        tokio::select! {
+            () = hangups.recv() => info!("Received SIGHUP, reloading configuration"),
+            _ = ticker.tick() => {
+                let current = modification_time(state).await.ok();
+                if current == modified {
+                    continue;
+                }
+
+                modified = current;
+                info!("Configuration changed, reloading it");
+            }
+        }
+
+        match reload(state, &table).await {
+            Ok(new_table) => table = new_table,
+            Err(e) => error!("Failed to reload configuration, keeping the current one: {e}"),
+        }
+    }
+}
+
+/// Parses the configuration file and, if it is valid, swaps it in for the current configuration.
+///
+/// Returns the TOML table that the new configuration was parsed from.
+async fn reload(state: &AppState, current: &toml::Table) -> Result<toml::Table, Box<dyn std::error::Error>> {
+    let (config, table) = initialise_config().await?;
+
+    let mut changed = Vec::new();
+    changed_keys(None, current, &table, &mut changed);
+
+    // the key file isn't part of the table, so changes to its keys are only noticed by comparing them
+    if changed.is_empty() && config.authentication() != state.config().authentication() {
+        changed.extend(config.api_key_file().map(|path| path.display().to_string()));
+    }
+
+    if changed.is_empty() {
+        info!("Configuration is unchanged");
+        return Ok(table);
+    }
+
+    let fixed: Vec<&str> = changed.iter()
+        .filter(|key| RESTART_REQUIRED.iter().any(|fixed| key == fixed || key.starts_with(&format!("{fixed}."))))
+        .map(String::as_str)
+        .collect();
+
+    if !fixed.is_empty() {
+        return Err(format!("{} can only be changed by restarting Pithos", fixed.join(", ")).into());
+    }
+
+    state.rate_limiters.requests.reconfigure(config.request_rate_limit());
+    state.rate_limiters.bytes.reconfigure(config.byte_rate_limit());
+    state.config.store(Arc::new(config));
+
+    info!("Reloaded configuration, changed {}", changed.join(", "));
+    Ok(table)
+}
+
+/// Collects the dotted paths of the keys whose values differ between two TOML tables.
+fn changed_keys(prefix: Option<&str>, old: &toml::Table, new: &toml::Table, changed: &mut Vec<String>) {
+    let mut keys: Vec<&String> = old.keys().chain(new.keys().filter(|key| !old.contains_key(*key))).collect();
+    keys.sort();
+
+    for key in keys {
+        let path = prefix.map_or_else(|| key.clone(), |prefix| format!("{prefix}.{key}"));
+
+        match (old.get(key), new.get(key)) {
+            (Some(toml::Value::Table(old)), Some(toml::Value::Table(new))) => changed_keys(Some(&path), old, new, changed),
+            (old, new) if old != new => changed.push(path),
+            _ => {}
+        }
+    }
+}
+
+/// Returns when the configuration file, or the API key file it names, was last modified.
+async fn modification_time(state: &AppState) -> io::Result<SystemTime> {
+    let mut modified = fs::metadata(CONFIG_PATH).await?.modified()?;
+    if let Some(key_file) = state.config().api_key_file() {
+        modified = modified.max(fs::metadata(key_file).await?.modified()?);
+    }
+
+    Ok(modified)
+}
+
+/// Receives the `SIGHUP` signals sent to the process.
+struct Hangups {
+    /// The stream of signals, if it could be registered.
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangups {
+    /// Starts receiving signals.
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let signal = signal(SignalKind::hangup())
+                .inspect_err(|e| error!("Failed to listen for SIGHUP, the configuration will only reload on file changes: {e}"))
+                .ok();
+
+            Self { signal }
+        }
+
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    /// Waits for the next signal, which never arrives on platforms without signals.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+
+        std::future::pending::<()>().await;
+    }
+}
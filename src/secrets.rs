@@ -0,0 +1,17 @@
+//! Generates and hashes the secrets that Pithos hands out to clients.
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+/// Generates a new random secret, encoded as hexadecimal.
+pub fn generate() -> String {
+    let mut secret = [0; 32];
+    OsRng.fill_bytes(&mut secret);
+    hex::encode(secret)
+}
+
+/// Hashes a secret for storage, so that it can be verified without being stored itself.
+pub fn hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
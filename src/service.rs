@@ -2,20 +2,33 @@
 
 use core::fmt::{self, Display, Formatter};
 use core::time::Duration;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use async_trait::async_trait;
 use google_cloud_storage::client::Client;
+use google_cloud_storage::http::buckets::get::GetBucketRequest;
+use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
+use google_cloud_storage::http::objects::get::GetObjectRequest;
 use google_cloud_storage::sign::{SignedURLMethod, SignedURLOptions};
+use http::{HeaderMap, HeaderValue};
+use http::header::CONTENT_LENGTH;
 use mime::Mime;
+use s3::Bucket;
+use tracing::instrument;
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
+use crate::digests::{Algorithm, ContentDigest, DigestQuery};
 use crate::errors::PithosError;
 use crate::file_extensions::FileExt;
+use crate::health::Check;
+use crate::{metadata, secrets, signed_urls};
 
 #[derive(Deserialize, Copy, Clone)]
 pub enum AvailableService {
     LocalStorage,
-    GoogleCloudStorage
+    GoogleCloudStorage,
+    S3
 }
 
 /// Represents a response to a file upload request.
@@ -25,6 +38,24 @@ pub struct UploadHandle {
     pub url: String,
     /// The UUID of the file, for downloading.
     pub uuid: Uuid,
+    /// The secret with which the uploader can delete the file.
+    pub deletion_token: String,
+    /// The headers that must be sent along with the upload, such as those carrying its digest.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl UploadHandle {
+    /// Creates a handle for uploading to the given URL, with a new deletion token.
+    pub fn new(url: String, uuid: Uuid) -> Self {
+        Self { url, uuid, deletion_token: secrets::generate(), headers: BTreeMap::new() }
+    }
+
+    /// Requires the given header to be sent along with the upload.
+    pub fn with_header(mut self, name: &str, value: String) -> Self {
+        self.headers.insert(name.to_string(), value);
+        self
+    }
 }
 
 /// Represents a response to a file download request.
@@ -34,24 +65,78 @@ pub struct DownloadHandle {
     pub url: String,
 }
 
+/// The properties of a file in the underlying storage.
+pub struct ObjectStat {
+    /// The size of the file, in bytes.
+    pub size: u64,
+    /// The UNIX timestamp, in seconds, at which the file was created, if the storage records it.
+    pub created_at: Option<u64>,
+}
+
 /// A service that can be used to generate URLs for accessing files.
 #[async_trait]
 pub trait Service: Display + Sync + Send {
-    async fn request_upload_url(&self, length: u64) -> Result<UploadHandle, PithosError>;
-    async fn request_download_url(&self, type_hint: Option<Mime>, extension_hint: Option<FileExt>, file_identifier: Uuid) -> Result<DownloadHandle, PithosError>;
+    /// Requests a URL for uploading a file of the given length, which the storage verifies against the digest, if one is given.
+    ///
+    /// The URL remains valid for the given lifetime, which is taken from the current configuration by the caller.
+    async fn request_upload_url(&self, length: u64, digest: Option<&ContentDigest>, lifetime: Duration) -> Result<UploadHandle, PithosError>;
+    /// Requests a URL for a resumable upload using the tus protocol.
+    async fn request_resumable_upload_url(&self, _length: u64, _digest: Option<&ContentDigest>, _lifetime: Duration) -> Result<UploadHandle, PithosError> {
+        Err(PithosError::ResumableUnsupported)
+    }
+    /// Requests a URL for downloading the file, which remains valid for the given lifetime.
+    async fn request_download_url(&self, type_hint: Option<Mime>, extension_hint: Option<FileExt>, file_identifier: Uuid, lifetime: Duration) -> Result<DownloadHandle, PithosError>;
+    /// Returns the properties of the file in the underlying storage, or `None` if it hasn't been uploaded.
+    async fn stat(&self, file_identifier: Uuid) -> Result<Option<ObjectStat>, PithosError>;
+    /// Returns whether the file has been uploaded to the underlying storage.
+    async fn has_object(&self, file_identifier: Uuid) -> Result<bool, PithosError> {
+        Ok(self.stat(file_identifier).await?.is_some())
+    }
+    /// Returns the UNIX timestamp, in seconds, at which an unfinished upload of the file was last written to,
+    /// or `None` if the storage can't tell or nothing of it has been written.
+    async fn last_upload_activity(&self, _file_identifier: Uuid) -> Result<Option<u64>, PithosError> {
+        Ok(None)
+    }
+    /// Deletes the file from the underlying storage, succeeding if it doesn't exist.
+    async fn delete(&self, file_identifier: Uuid) -> Result<(), PithosError>;
+    /// Returns whether downloads are served by Pithos itself, so that completed transfers can be counted
+    /// instead of issued download URLs.
+    fn counts_downloads(&self) -> bool {
+        false
+    }
+    /// Checks that the underlying storage is usable, for the readiness endpoint.
+    ///
+    /// Local storage additionally checks that it has at least `min_free_space` bytes free.
+    async fn check_readiness(&self, min_free_space: u64) -> Vec<Check>;
 }
 
 pub struct LocalStorage {
     upload_path: String,
     download_path: String,
+    tus_endpoint: String,
+    storage_path: PathBuf,
+    staging_path: PathBuf,
 }
 
 impl LocalStorage {
-    pub fn new(upload_path: &str, download_path: &str) -> Self {
-        Self { upload_path: upload_path.to_string(), download_path: download_path.to_string() }
+    pub fn new(upload_path: &str, download_path: &str, tus_endpoint: &str, storage_path: PathBuf, staging_path: PathBuf) -> Self {
+        Self {
+            upload_path: upload_path.to_string(),
+            download_path: download_path.to_string(),
+            tus_endpoint: tus_endpoint.to_string(),
+            storage_path,
+            staging_path,
+        }
     }
 }
 
+/// Returns the signed query parameters of a local upload URL, which carry the declared length and digest of the upload.
+fn upload_query(length: u64, digest: Option<&ContentDigest>) -> HashMap<&'static str, String> {
+    let mut query = HashMap::from([("length", length.to_string())]);
+    query.extend(digest.map(DigestQuery::parameter));
+    query
+}
+
 impl Display for LocalStorage {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "Local Storage")
@@ -60,15 +145,24 @@ impl Display for LocalStorage {
 
 #[async_trait]
 impl Service for LocalStorage {
-    async fn request_upload_url(&self, _: u64) -> Result<UploadHandle, PithosError> {
+    #[instrument(skip_all, fields(service = %self, length = length))]
+    async fn request_upload_url(&self, length: u64, digest: Option<&ContentDigest>, lifetime: Duration) -> Result<UploadHandle, PithosError> {
+        let uuid = Uuid::new_v4();
+
+        let url = signed_urls::build(&format!("{}/{}", self.upload_path, uuid), upload_query(length, digest), lifetime)?;
+        Ok(UploadHandle::new(url, uuid))
+    }
+
+    #[instrument(skip_all, fields(service = %self, length = length))]
+    async fn request_resumable_upload_url(&self, length: u64, digest: Option<&ContentDigest>, lifetime: Duration) -> Result<UploadHandle, PithosError> {
         let uuid = Uuid::new_v4();
 
-        let url = axum_signed_urls::build(&format!("{}/{}", self.upload_path, uuid), HashMap::new())
-            .map_err(|e| { PithosError::Access(e.into()) })?;
-        Ok(UploadHandle { url, uuid })
+        let url = signed_urls::build(&format!("{}/{}", self.tus_endpoint, uuid), upload_query(length, digest), lifetime)?;
+        Ok(UploadHandle::new(url, uuid))
     }
 
-    async fn request_download_url(&self, hint: Option<Mime>, ext_hint: Option<FileExt>, file_identifier: Uuid) -> Result<DownloadHandle, PithosError> {
+    #[instrument(skip_all, fields(service = %self, file = %file_identifier))]
+    async fn request_download_url(&self, hint: Option<Mime>, ext_hint: Option<FileExt>, file_identifier: Uuid, lifetime: Duration) -> Result<DownloadHandle, PithosError> {
         let mut query = HashMap::new();
 
         if let Some(hint) = hint {
@@ -79,11 +173,102 @@ impl Service for LocalStorage {
             query.insert("ext_hint", ext_hint.0);
         }
 
-        let url = axum_signed_urls::build(&format!("{}/{}", self.download_path, file_identifier), query.iter().map(|(k, v)| (*k, v.as_str())).collect())
-            .map_err(|e| { PithosError::Access(e.into()) })?;
+        let url = signed_urls::build(&format!("{}/{}", self.download_path, file_identifier), query, lifetime)?;
 
         Ok(DownloadHandle { url })
     }
+
+    async fn stat(&self, file_identifier: Uuid) -> Result<Option<ObjectStat>, PithosError> {
+        let metadata = match tokio::fs::metadata(self.storage_path.join(file_identifier.to_string())).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PithosError::ServerError(Box::new(e))),
+        };
+
+        // not every file system records creation times, but a stored file is never modified after its upload
+        let created_at = metadata.created().or_else(|_| metadata.modified()).ok().map(metadata::timestamp);
+
+        Ok(Some(ObjectStat { size: metadata.len(), created_at }))
+    }
+
+    async fn last_upload_activity(&self, file_identifier: Uuid) -> Result<Option<u64>, PithosError> {
+        let mut entries = match tokio::fs::read_dir(&self.staging_path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PithosError::ServerError(Box::new(e))),
+        };
+
+        // resumable uploads are staged under the file's name, and single-request uploads under names that start with it
+        let name = file_identifier.to_string();
+        let mut last_activity = None;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| PithosError::ServerError(Box::new(e)))? {
+            let entry_name = entry.file_name();
+            let entry_name = entry_name.to_string_lossy();
+            if entry_name != name && !entry_name.starts_with(&format!("{name}.")) {
+                continue;
+            }
+
+            // the file may have been finalised or removed since the directory was read
+            if let Ok(modified) = entry.metadata().await.and_then(|metadata| metadata.modified()) {
+                last_activity = last_activity.max(Some(metadata::timestamp(modified)));
+            }
+        }
+
+        Ok(last_activity)
+    }
+
+    async fn delete(&self, file_identifier: Uuid) -> Result<(), PithosError> {
+        // unfinished resumable uploads are staged under the file's name as well
+        for directory in [&self.storage_path, &self.staging_path] {
+            match tokio::fs::remove_file(directory.join(file_identifier.to_string())).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(PithosError::ServerError(Box::new(e))),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn counts_downloads(&self) -> bool {
+        true
+    }
+
+    async fn check_readiness(&self, min_free_space: u64) -> Vec<Check> {
+        // writing a file is the only reliable test, as permissions alone don't account for read-only mounts
+        let probe = self.storage_path.join(format!(".readiness-probe.{}", Uuid::new_v4()));
+        let writable = async {
+            // the local storage is only created once the first upload is staged
+            tokio::fs::create_dir_all(&self.storage_path).await?;
+            tokio::fs::write(&probe, b"").await?;
+            tokio::fs::remove_file(&probe).await
+        }.await;
+
+        vec![
+            Check::from_result("local_storage_writable", writable),
+            free_space_check(&self.storage_path, min_free_space),
+        ]
+    }
+}
+
+/// Checks that the file system containing the given path has at least the given number of bytes free.
+#[cfg(unix)]
+fn free_space_check(path: &Path, min_free_space: u64) -> Check {
+    const NAME: &str = "local_storage_free_space";
+
+    match rustix::fs::statvfs(path) {
+        Ok(stats) => {
+            let free_space = stats.f_bavail.saturating_mul(stats.f_frsize);
+            let detail = format!("{free_space} bytes free, {min_free_space} bytes required");
+            if free_space >= min_free_space { Check::passed(NAME, Some(detail)) } else { Check::failed(NAME, detail) }
+        }
+        Err(e) => Check::failed(NAME, e),
+    }
+}
+
+/// Skips checking the free space, as it can only be looked up on Unix.
+#[cfg(not(unix))]
+fn free_space_check(_: &Path, _: u64) -> Check {
+    Check::passed("local_storage_free_space", Some("free space can only be checked on Unix".to_string()))
 }
 
 /// A service that uses Google Cloud Storage to store files.
@@ -112,33 +297,181 @@ impl Display for GoogleCloudStorage {
 
 #[async_trait]
 impl Service for GoogleCloudStorage {
-    async fn request_upload_url(&self, length: u64) -> Result<UploadHandle, PithosError> {
+    #[instrument(skip_all, fields(service = %self, length = length))]
+    async fn request_upload_url(&self, length: u64, digest: Option<&ContentDigest>, lifetime: Duration) -> Result<UploadHandle, PithosError> {
         let uuid = Uuid::new_v4();
 
+        // Google Cloud Storage only verifies CRC32C and MD5 digests, which it takes from the `x-goog-hash` header
+        let hash_header = match digest {
+            Some(digest) if digest.algorithm == Algorithm::Crc32c => Some(format!("crc32c={}", digest.base64())),
+            Some(digest) => return Err(PithosError::UnsupportedDigest(digest.algorithm)),
+            None => None,
+        };
+
+        let mut headers = vec![format!("Content-Length: {length}")];
+        headers.extend(hash_header.iter().map(|value| format!("x-goog-hash: {value}")));
+
         let url = self.client.signed_url(
             &self.bucket_name,
             &uuid.to_string(),
             None, None, SignedURLOptions {
                 method: SignedURLMethod::PUT,
-                headers: vec![format!("Content-Length: {length}")],
-                expires: Duration::from_secs(1800),
+                headers,
+                expires: lifetime,
                 ..Default::default()
             }
         ).await?;
 
-        Ok(UploadHandle { url, uuid })
+        let handle = UploadHandle::new(url, uuid);
+        Ok(match hash_header {
+            Some(value) => handle.with_header("x-goog-hash", value),
+            None => handle,
+        })
     }
 
-    async fn request_download_url(&self, _: Option<Mime>, _: Option<FileExt>, file_identifier: Uuid) -> Result<DownloadHandle, PithosError> {
+    #[instrument(skip_all, fields(service = %self, file = %file_identifier))]
+    async fn request_download_url(&self, _: Option<Mime>, _: Option<FileExt>, file_identifier: Uuid, lifetime: Duration) -> Result<DownloadHandle, PithosError> {
         Ok(DownloadHandle {
             url: self.client.signed_url(
             &self.bucket_name,
             &file_identifier.to_string(),
             None, None, SignedURLOptions {
                 method: SignedURLMethod::GET,
-                expires: Duration::from_secs(1800),
+                expires: lifetime,
                 ..Default::default()
             }).await?
         })
     }
+
+    async fn stat(&self, file_identifier: Uuid) -> Result<Option<ObjectStat>, PithosError> {
+        let request = GetObjectRequest {
+            bucket: self.bucket_name.clone(),
+            object: file_identifier.to_string(),
+            ..Default::default()
+        };
+
+        match self.client.get_object(&request).await {
+            Ok(object) => Ok(Some(ObjectStat {
+                size: u64::try_from(object.size).unwrap_or_default(),
+                created_at: object.time_created.and_then(|time| u64::try_from(time.unix_timestamp()).ok()),
+            })),
+            Err(google_cloud_storage::http::Error::Response(response)) if response.code == 404 => Ok(None),
+            Err(e) => Err(PithosError::ServerError(Box::new(e))),
+        }
+    }
+
+    async fn delete(&self, file_identifier: Uuid) -> Result<(), PithosError> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket_name.clone(),
+            object: file_identifier.to_string(),
+            ..Default::default()
+        };
+
+        match self.client.delete_object(&request).await {
+            Err(google_cloud_storage::http::Error::Response(response)) if response.code == 404 => Ok(()),
+            result => result.map_err(|e| PithosError::ServerError(Box::new(e))),
+        }
+    }
+
+    async fn check_readiness(&self, _min_free_space: u64) -> Vec<Check> {
+        let request = GetBucketRequest {
+            bucket: self.bucket_name.clone(),
+            ..Default::default()
+        };
+
+        vec![Check::from_result("gcs_bucket_reachable", self.client.get_bucket(&request).await.map(drop))]
+    }
+}
+
+/// A service that uses an S3-compatible object store, such as `MinIO`, Ceph or Garage, to store files.
+pub struct S3Storage {
+    /// The bucket in which files are stored.
+    bucket: Box<Bucket>,
+}
+
+impl S3Storage {
+    /// Creates a new S3-compatible storage service.
+    pub const fn with_bucket(bucket: Box<Bucket>) -> Self {
+        Self { bucket }
+    }
+}
+
+/// Converts a URL lifetime to the whole seconds expected by S3, saturating if it is too long.
+fn expiry_seconds(lifetime: Duration) -> u32 {
+    u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX)
+}
+
+impl Display for S3Storage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "S3-compatible Storage")
+    }
+}
+
+#[async_trait]
+impl Service for S3Storage {
+    #[instrument(skip_all, fields(service = %self, length = length))]
+    async fn request_upload_url(&self, length: u64, digest: Option<&ContentDigest>, lifetime: Duration) -> Result<UploadHandle, PithosError> {
+        let uuid = Uuid::new_v4();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+
+        // S3 verifies the additional checksum of an upload if it is sent in the matching header
+        let checksum_header = digest.map(|digest| {
+            let name = match digest.algorithm {
+                Algorithm::Sha256 => "x-amz-checksum-sha256",
+                Algorithm::Crc32c => "x-amz-checksum-crc32c",
+            };
+            (name, digest.base64())
+        });
+
+        if let Some((name, value)) = &checksum_header {
+            headers.insert(*name, HeaderValue::try_from(value).map_err(|e| PithosError::Access(Box::new(e)))?);
+        }
+
+        let url = self.bucket.presign_put(uuid.to_string(), expiry_seconds(lifetime), Some(headers), None).await?;
+
+        let handle = UploadHandle::new(url, uuid);
+        Ok(match checksum_header {
+            Some((name, value)) => handle.with_header(name, value),
+            None => handle,
+        })
+    }
+
+    #[instrument(skip_all, fields(service = %self, file = %file_identifier))]
+    async fn request_download_url(&self, _: Option<Mime>, _: Option<FileExt>, file_identifier: Uuid, lifetime: Duration) -> Result<DownloadHandle, PithosError> {
+        Ok(DownloadHandle {
+            url: self.bucket.presign_get(file_identifier.to_string(), expiry_seconds(lifetime), None).await?
+        })
+    }
+
+    async fn stat(&self, file_identifier: Uuid) -> Result<Option<ObjectStat>, PithosError> {
+        let (head, status) = self.bucket.head_object(file_identifier.to_string()).await?;
+        match status {
+            200 => Ok(Some(ObjectStat {
+                size: head.content_length.and_then(|length| u64::try_from(length).ok()).unwrap_or_default(),
+                created_at: head.last_modified.and_then(|time| httpdate::parse_http_date(&time).ok()).map(metadata::timestamp),
+            })),
+            404 => Ok(None),
+            status => Err(PithosError::ServerError(format!("the S3-compatible store responded with status {status}").into())),
+        }
+    }
+
+    async fn delete(&self, file_identifier: Uuid) -> Result<(), PithosError> {
+        match self.bucket.delete_object(file_identifier.to_string()).await?.status_code() {
+            200 | 204 | 404 => Ok(()),
+            status => Err(PithosError::ServerError(format!("the S3-compatible store responded with status {status}").into())),
+        }
+    }
+
+    async fn check_readiness(&self, _min_free_space: u64) -> Vec<Check> {
+        // listing a single object is the cheapest request that needs both the bucket and the credentials to work
+        let result = match self.bucket.list_page(String::new(), None, None, None, Some(1)).await {
+            Ok((_, 200)) => Ok(()),
+            Ok((_, status)) => Err(format!("the S3-compatible store responded with status {status}").into()),
+            Err(e) => Err(Box::<dyn std::error::Error>::from(e)),
+        };
+
+        vec![Check::from_result("s3_bucket_reachable", result)]
+    }
 }
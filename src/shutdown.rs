@@ -0,0 +1,60 @@
+//! Shuts Pithos down gracefully when it is asked to stop.
+//!
+//! Once a shutdown is requested, new connections are refused and in-flight requests are given the configured
+//! grace period to finish. Uploads that are cut off when it ends leave temporary files behind, which are removed
+//! before the process exits, along with the Unix domain socket.
+
+use tracing::{error, info};
+
+use crate::config::ListenOptions;
+use crate::{AppState, listeners, staging};
+
+/// Waits until the process is asked to stop, with `SIGTERM` or `Ctrl+C`.
+pub async fn requested() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => { terminate.recv().await; }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => info!("Received Ctrl+C"),
+        () = terminate => info!("Received SIGTERM"),
+    }
+}
+
+/// Removes the temporary files of uploads that didn't finish within the grace period, and the Unix domain socket.
+///
+/// This is only done once the connections have finished or been abandoned, so that none of them is still
+/// writing to a temporary file or being served on the socket.
+pub async fn clean_up(state: &AppState) {
+    let config = state.config();
+
+    match staging::remove_temporary_files(&config).await {
+        Ok(0) => {}
+        Ok(removed) => info!("Removed {removed} temporary files of unfinished uploads"),
+        Err(e) => error!("Failed to remove temporary files of unfinished uploads: {e}"),
+    }
+
+    if let Some(path) = config.listen_options().as_ref().and_then(ListenOptions::unix_socket)
+        && let Err(e) = listeners::remove_socket(path) {
+        error!("Failed to remove the Unix domain socket at {path}: {e}", path = path.display());
+    }
+}
@@ -0,0 +1,52 @@
+//! Builds and verifies the expiring signed URLs used to access the local Pithos storage.
+
+use core::time::Duration;
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use axum_signed_urls::SignedUrl;
+use serde::Deserialize;
+
+use crate::errors::PithosError;
+use crate::metadata::now;
+
+/// The query parameter holding the UNIX timestamp, in seconds, after which a URL is no longer valid.
+const EXPIRY_PARAMETER: &str = "expires";
+
+/// The signed expiry timestamp of a URL.
+#[derive(Deserialize)]
+struct ExpiryQuery {
+    expires: u64,
+}
+
+/// Builds a signed URL for the given path and query parameters, which expires after the given lifetime.
+pub fn build(path: &str, mut query: HashMap<&str, String>, lifetime: Duration) -> Result<String, PithosError> {
+    query.insert(EXPIRY_PARAMETER, now().saturating_add(lifetime.as_secs()).to_string());
+
+    axum_signed_urls::build(path, query.iter().map(|(k, v)| (*k, v.as_str())).collect())
+        .map_err(|e| PithosError::Access(e.into()))
+}
+
+/// Extractor for signed URLs that have not yet expired.
+pub struct UnexpiredSignedUrl;
+
+#[async_trait]
+impl<S: Sync> FromRequestParts<S> for UnexpiredSignedUrl {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        SignedUrl::from_request_parts(parts, state).await.map_err(IntoResponse::into_response)?;
+
+        let Query(query) = Query::<ExpiryQuery>::try_from_uri(&parts.uri)
+            .map_err(|e| PithosError::from(e).into_response())?;
+
+        if now() > query.expires {
+            return Err(PithosError::ExpiredUrl(query.expires).into_response());
+        }
+
+        Ok(Self)
+    }
+}
@@ -0,0 +1,112 @@
+//! Manages files that are still being uploaded to the local Pithos storage.
+//!
+//! Uploads are written to a staging directory and only moved into the local storage once they
+//! are complete, so that a failed upload can never be served and a finished one is never overwritten.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{self, File};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::PithosError;
+use crate::metadata::MetadataStore;
+
+/// Returns the path at which the given upload is stored once it is complete.
+pub fn stored_file(config: &Config, uuid: Uuid) -> PathBuf {
+    config.local_storage_path().join(uuid.to_string())
+}
+
+/// Returns the path of a staging file with the given name.
+pub fn staged_file(config: &Config, name: &str) -> PathBuf {
+    config.staging_path().join(name)
+}
+
+/// Returns the path of a new staging file for a single-request upload of the given file.
+///
+/// Each request gets a file of its own, so that concurrent uploads of the same file can't interleave.
+/// Unlike resumable uploads, whose staging files are named after the file alone, these files are temporary:
+/// they are useless once their request ends.
+pub fn temporary_file(config: &Config, uuid: Uuid) -> PathBuf {
+    staged_file(config, &format!("{uuid}.{}", Uuid::new_v4()))
+}
+
+/// Removes the temporary staging files of single-request uploads, returning how many were removed.
+///
+/// Staging files of resumable uploads are kept, as clients may still resume them.
+pub async fn remove_temporary_files(config: &Config) -> std::io::Result<usize> {
+    let mut entries = match fs::read_dir(config.staging_path()).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().contains('.') {
+            fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Creates the staging directory, and the local storage directory that contains it, if they are missing.
+pub async fn create_staging_dir(config: &Config) -> Result<(), PithosError> {
+    fs::create_dir_all(config.staging_path()).await
+        .map_err(|e| PithosError::ServerError(Box::new(e)))
+}
+
+/// Returns whether the given upload has been stored.
+pub async fn is_stored(config: &Config, uuid: Uuid) -> Result<bool, PithosError> {
+    fs::try_exists(stored_file(config, uuid)).await
+        .map_err(|e| PithosError::ServerError(Box::new(e)))
+}
+
+/// Returns whether the given upload's file has been deleted, such as while it was being uploaded.
+pub async fn is_deleted(metadata: &MetadataStore, uuid: Uuid) -> Result<bool, PithosError> {
+    Ok(metadata.get(uuid).await?.is_some_and(|record| record.deleted_at.is_some()))
+}
+
+/// Moves a complete, synced staging file into the local storage as the given upload, and records it as complete.
+///
+/// Fails with [`PithosError::AlreadyExists`] instead of replacing an upload that was already stored,
+/// in which case the staging file is discarded. Fails with [`PithosError::Gone`] if the file was deleted
+/// while it was being uploaded, in which case the upload is discarded.
+#[instrument(skip(config, metadata, staged))]
+pub async fn finalise(config: &Config, metadata: &MetadataStore, staged: &Path, uuid: Uuid) -> Result<(), PithosError> {
+    let stored = stored_file(config, uuid);
+
+    if is_deleted(metadata, uuid).await? {
+        let _ = fs::remove_file(staged).await;
+        return Err(PithosError::Gone);
+    }
+
+    // unlike a rename, a hard link never replaces an existing file
+    let linked = fs::hard_link(staged, &stored).await;
+    let _ = fs::remove_file(staged).await;
+
+    match linked {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(PithosError::AlreadyExists),
+        // a resumable upload's staging file is removed when its file is deleted
+        Err(e) if e.kind() == ErrorKind::NotFound && is_deleted(metadata, uuid).await? => return Err(PithosError::Gone),
+        Err(e) => return Err(PithosError::ServerError(Box::new(e))),
+    }
+
+    // persist the new directory entry, where the platform allows syncing directories
+    if let Ok(directory) = File::open(config.local_storage_path()).await {
+        let _ = directory.sync_all().await;
+    }
+
+    // deletions are recorded before the file is removed, so one that missed the file is always seen here
+    if metadata.get(uuid).await?.is_some_and(|record| record.deleted_at.is_some()) {
+        fs::remove_file(&stored).await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+        return Err(PithosError::Gone);
+    }
+
+    metadata.record_completed(uuid).await
+}
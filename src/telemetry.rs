@@ -0,0 +1,65 @@
+//! Exports traces to an OpenTelemetry collector over OTLP, as configured in the `[server.tracing]` table.
+//!
+//! Requests continue the trace in their W3C `traceparent` header, if they have one, so that the spans of Pithos
+//! join the traces of the clients and proxies in front of it.
+
+use axum::http::{HeaderMap, HeaderName};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::{global, Context};
+use opentelemetry_otlp::{ExporterBuildError, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::error;
+
+use crate::config::{OtlpProtocol, TracingOptions};
+
+/// Creates the provider of the tracer that exports spans to the configured collector, in batches.
+///
+/// This also makes requests' `traceparent` headers be honoured, which they otherwise aren't.
+pub fn tracer_provider(options: &TracingOptions) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = match options.protocol() {
+        OtlpProtocol::Grpc => SpanExporter::builder().with_tonic()
+            .with_endpoint(options.otlp_endpoint())
+            .build()?,
+        OtlpProtocol::HttpProtobuf => SpanExporter::builder().with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .with_endpoint(options.otlp_endpoint())
+            .build()?,
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(options.service_name().to_string()).build())
+        .build())
+}
+
+/// Reads the trace context from the headers of a request.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Returns the trace context that a request was sent with, which is empty if it has none or traces aren't exported.
+pub fn remote_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Exports the spans that are still waiting in the batch, and stops exporting.
+pub async fn shutdown(provider: SdkTracerProvider) {
+    // the batch is exported on a thread of its own, which shutting down waits for
+    match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Failed to export the remaining traces: {e}"),
+        Err(e) => error!("Failed to export the remaining traces: {e}"),
+    }
+}
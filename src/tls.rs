@@ -0,0 +1,143 @@
+//! Terminates TLS connections, using the certificate and key configured in the `[server.tls]` table.
+//!
+//! The certificate and key are reloaded whenever their files change, so that renewed certificates are picked up
+//! without a restart. Connections that are already open keep the certificate they were established with.
+
+use core::time::Duration;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::extract::Host;
+use axum::http::Uri;
+use axum::response::Redirect;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use tokio::fs;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{error, info};
+
+use crate::config::TlsOptions;
+
+/// How often the certificate files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_mins(1);
+
+/// Reads the certificate chain from a PEM file.
+async fn read_certificates(path: &Path) -> Result<Vec<Certificate>, Box<dyn std::error::Error>> {
+    let pem = fs::read(path).await?;
+    let certificates: Vec<Certificate> = rustls_pemfile::certs(&mut pem.as_slice())?.into_iter().map(Certificate).collect();
+
+    if certificates.is_empty() {
+        return Err(format!("{} contains no certificates", path.display()).into());
+    }
+
+    Ok(certificates)
+}
+
+/// Reads the first private key from a PEM file.
+async fn read_private_key(path: &Path) -> Result<PrivateKey, Box<dyn std::error::Error>> {
+    let pem = fs::read(path).await?;
+
+    rustls_pemfile::read_all(&mut pem.as_slice())?.into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("{} contains no private key", path.display()).into())
+}
+
+/// Builds the TLS configuration from the configured certificate, key and client CA files.
+async fn server_config(options: &TlsOptions) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+    let certificates = read_certificates(options.cert_path()).await?;
+    let key = read_private_key(options.key_path()).await?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match options.client_ca_path() {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(path).await? {
+                roots.add(&certificate)?;
+            }
+
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certificates, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// Loads the TLS configuration for the first time.
+pub async fn load(options: &TlsOptions) -> Result<RustlsConfig, Box<dyn std::error::Error>> {
+    Ok(RustlsConfig::from_config(server_config(options).await?))
+}
+
+/// Returns when any of the configured certificate and key files was last modified.
+async fn modification_time(options: &TlsOptions) -> io::Result<SystemTime> {
+    let mut latest = SystemTime::UNIX_EPOCH;
+    for path in [Some(options.cert_path()), Some(options.key_path()), options.client_ca_path()].into_iter().flatten() {
+        latest = latest.max(fs::metadata(path).await?.modified()?);
+    }
+
+    Ok(latest)
+}
+
+/// Reloads the TLS configuration whenever the certificate files change, for the lifetime of the program.
+///
+/// If the new files can't be loaded, e.g. because only one of them has been replaced yet, the current
+/// configuration is kept until they change again.
+pub async fn watch(options: TlsOptions, config: RustlsConfig) {
+    let mut ticker = interval(POLL_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut modified = modification_time(&options).await.ok();
+
+    loop {
+        ticker.tick().await;
+
+        let current = modification_time(&options).await.ok();
+        if current == modified {
+            continue;
+        }
+
+        modified = current;
+        match server_config(&options).await {
+            Ok(server_config) => {
+                config.reload_from_config(server_config);
+                info!("Reloaded TLS certificate from {path}", path = options.cert_path().display());
+            }
+            Err(e) => error!("Failed to reload TLS certificate, keeping the current one: {e}"),
+        }
+    }
+}
+
+/// Redirects every request to the same URL over HTTPS.
+///
+/// The port of the HTTPS listener is given explicitly, as it usually differs from the one the request was sent to.
+fn redirect(host: &str, uri: &Uri, https_port: u16) -> Redirect {
+    // the host may be an IPv6 literal, whose colons are inside brackets
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if !port.contains(']') => hostname,
+        _ => host,
+    };
+
+    let authority = if https_port == 443 { hostname.to_string() } else { format!("{hostname}:{https_port}") };
+    let path_and_query = uri.path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+
+    Redirect::permanent(&format!("https://{authority}{path_and_query}"))
+}
+
+/// Serves plaintext HTTP on the given address, redirecting every request to the HTTPS listener on the given port.
+pub async fn redirect_to_https(addr: SocketAddr, https_port: u16) -> io::Result<()> {
+    let app = Router::new().fallback(move |Host(host): Host, uri: Uri| async move { redirect(&host, &uri, https_port) });
+
+    info!("Redirecting plaintext HTTP on {addr} to HTTPS");
+    axum_server::bind(addr).serve(app.into_make_service()).await
+}
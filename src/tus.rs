@@ -0,0 +1,303 @@
+//! Implements resumable uploads to the local Pithos storage using the tus 1.0 protocol,
+//! along with its creation and termination extensions.
+//!
+//! See <https://tus.io/protocols/resumable-upload> for the protocol specification.
+
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+
+use async_trait::async_trait;
+use axum::extract::{BodyStream, FromRequestParts, OriginalUri, Path, Query, State};
+use axum::headers::{self, HeaderMapExt};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::http::header::{CACHE_CONTROL, LOCATION};
+use axum::http::request::Parts;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::TypedHeader;
+use futures::TryStreamExt;
+use mime::Mime;
+use serde::Deserialize;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::config::Config;
+use crate::custom_headers::{TUS_EXTENSION, TUS_MAX_SIZE, TUS_RESUMABLE, TUS_VERSION, UploadLength, UploadOffset};
+use crate::digests::{self, ContentDigest, DigestQuery};
+use crate::errors::PithosError;
+use crate::signed_urls::UnexpiredSignedUrl;
+use crate::staging;
+
+/// The path under which resumable uploads are served.
+pub const TUS_ENDPOINT: &str = "/signed_tus";
+/// The version of the tus protocol that Pithos implements.
+pub const TUS_SUPPORTED_VERSION: &str = "1.0.0";
+/// The tus protocol extensions that Pithos implements.
+const TUS_SUPPORTED_EXTENSIONS: &str = "creation,termination";
+/// The content type that upload chunks must be sent with.
+const CHUNK_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// The uploads that are currently being written to or terminated.
+static ACTIVE_UPLOADS: Mutex<BTreeSet<Uuid>> = Mutex::new(BTreeSet::new());
+
+/// Grants exclusive access to an upload for as long as it is held.
+struct UploadLock(Uuid);
+
+impl UploadLock {
+    /// Locks the given upload, failing if another request already holds the lock.
+    fn acquire(uuid: Uuid) -> Result<Self, PithosError> {
+        if !ACTIVE_UPLOADS.lock().unwrap_or_else(PoisonError::into_inner).insert(uuid) {
+            return Err(PithosError::Locked);
+        }
+
+        Ok(Self(uuid))
+    }
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        ACTIVE_UPLOADS.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.0);
+    }
+}
+
+/// Returns whether a request is currently writing to or terminating the given upload.
+pub fn is_active(uuid: Uuid) -> bool {
+    ACTIVE_UPLOADS.lock().unwrap_or_else(PoisonError::into_inner).contains(&uuid)
+}
+
+/// Checks that the request was made with the supported version of the tus protocol.
+pub fn check_version(headers: &HeaderMap) -> Result<(), PithosError> {
+    match headers.get(TUS_RESUMABLE) {
+        Some(version) if version == TUS_SUPPORTED_VERSION => Ok(()),
+        _ => Err(PithosError::UnsupportedTusVersion),
+    }
+}
+
+/// Rejects requests that weren't made with the supported version of the tus protocol.
+pub struct TusResumable;
+
+#[async_trait]
+impl<S: Sync> FromRequestParts<S> for TusResumable {
+    type Rejection = PithosError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        check_version(&parts.headers).map(|()| Self)
+    }
+}
+
+/// The signed query parameters of a resumable upload URL.
+#[derive(Deserialize)]
+pub struct ResumableQuery {
+    /// The total size of the upload, as declared when the URL was requested.
+    length: u64,
+}
+
+/// Returns the path at which the given upload is staged until it completes.
+fn staging_file(config: &Config, uuid: Uuid) -> PathBuf {
+    staging::staged_file(config, &uuid.to_string())
+}
+
+/// Returns how many bytes of the given upload have been received, or `None` if it doesn't exist.
+async fn current_offset(config: &Config, uuid: Uuid, length: u64) -> Result<Option<u64>, PithosError> {
+    match fs::metadata(staging_file(config, uuid)).await {
+        Ok(metadata) => return Ok(Some(metadata.len())),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(PithosError::ServerError(Box::new(e))),
+    }
+
+    Ok(staging::is_stored(config, uuid).await?.then_some(length))
+}
+
+/// Moves a synced, completed upload into the local storage and records its completion.
+///
+/// If the upload doesn't match the digest declared for it, it is discarded instead.
+async fn complete(state: &AppState, uuid: Uuid, digest: Option<ContentDigest>) -> Result<(), PithosError> {
+    let config = state.config();
+    let staged = staging_file(&config, uuid);
+
+    // deleting the file removes its staging file, possibly while the last chunk was being written to it
+    if staging::is_deleted(&state.metadata, uuid).await? {
+        return Err(PithosError::Gone);
+    }
+
+    if let Some(expected) = digest {
+        let actual = match digests::of_file(&staged, expected.algorithm).await {
+            Ok(actual) => actual,
+            Err(e) if e.kind() == ErrorKind::NotFound && staging::is_deleted(&state.metadata, uuid).await? => return Err(PithosError::Gone),
+            Err(e) => return Err(PithosError::ServerError(Box::new(e))),
+        };
+
+        if actual != expected {
+            let _ = fs::remove_file(&staged).await;
+            return Err(PithosError::DigestMismatch);
+        }
+    }
+
+    staging::finalise(&config, &state.metadata, &staged, uuid).await
+}
+
+/// Describes the tus protocol support of the server in response to `OPTIONS` requests for resumable uploads.
+///
+/// This is a middleware rather than a handler, as the CORS layer answers every `OPTIONS` request by itself.
+pub async fn describe<B: Send>(State(state): State<&'static AppState>, request: Request<B>, next: Next<B>) -> Response {
+    let is_discovery = request.method() == Method::OPTIONS && request.uri().path().starts_with(TUS_ENDPOINT);
+
+    let mut response = next.run(request).await;
+    if is_discovery {
+        let headers = response.headers_mut();
+        headers.insert(TUS_VERSION, HeaderValue::from_static(TUS_SUPPORTED_VERSION));
+        headers.insert(TUS_EXTENSION, HeaderValue::from_static(TUS_SUPPORTED_EXTENSIONS));
+        headers.insert(TUS_MAX_SIZE, HeaderValue::from(state.config().max_upload_size()));
+    }
+
+    response
+}
+
+/// Creates a resumable upload, as per the tus creation extension.
+#[axum::debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_handler(
+    State(state): State<&'static AppState>,
+    _: UnexpiredSignedUrl,
+    _: TusResumable,
+    Path(uuid): Path<Uuid>,
+    Query(query): Query<ResumableQuery>,
+    Query(digest): Query<DigestQuery>,
+    OriginalUri(uri): OriginalUri,
+    TypedHeader(UploadLength(length)): TypedHeader<UploadLength>,
+) -> Result<(StatusCode, HeaderMap), PithosError> {
+    let config = state.config();
+
+    if length != query.length {
+        return Err(PithosError::SizeMismatch(query.length, length));
+    }
+
+    staging::create_staging_dir(&config).await?;
+
+    if current_offset(&config, uuid, length).await?.is_some() {
+        return Err(PithosError::AlreadyExists);
+    }
+
+    let file = OpenOptions::new().write(true).create_new(true).open(staging_file(&config, uuid)).await
+        .map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => PithosError::AlreadyExists,
+            _ => PithosError::ServerError(Box::new(e))
+        })?;
+
+    if length == 0 {
+        file.sync_all().await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+        drop(file);
+        complete(state, uuid, digest.digest()).await?;
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(LOCATION, HeaderValue::try_from(uri.to_string()).map_err(|e| PithosError::ServerError(Box::new(e)))?);
+    headers.typed_insert(UploadOffset(0));
+
+    Ok((StatusCode::CREATED, headers))
+}
+
+/// Reports how many bytes of a resumable upload the server has received.
+#[axum::debug_handler]
+pub async fn offset_handler(
+    State(state): State<&'static AppState>,
+    _: UnexpiredSignedUrl,
+    _: TusResumable,
+    Path(uuid): Path<Uuid>,
+    Query(query): Query<ResumableQuery>,
+) -> Result<HeaderMap, PithosError> {
+    let offset = current_offset(&state.config(), uuid, query.length).await?
+        .ok_or(PithosError::NoSuchFile)?;
+
+    let mut headers = HeaderMap::new();
+    headers.typed_insert(UploadOffset(offset));
+    headers.typed_insert(UploadLength(query.length));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+    Ok(headers)
+}
+
+/// Appends a chunk to a resumable upload, completing it once all of its bytes have been received.
+#[axum::debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn append_handler(
+    State(state): State<&'static AppState>,
+    _: UnexpiredSignedUrl,
+    _: TusResumable,
+    Path(uuid): Path<Uuid>,
+    Query(query): Query<ResumableQuery>,
+    Query(digest): Query<DigestQuery>,
+    TypedHeader(UploadOffset(offset)): TypedHeader<UploadOffset>,
+    content_type: Option<TypedHeader<headers::ContentType>>,
+    body: BodyStream,
+) -> Result<(StatusCode, TypedHeader<UploadOffset>), PithosError> {
+    let config = state.config();
+
+    let is_chunk = content_type.is_some_and(|TypedHeader(content_type)| Mime::from(content_type).essence_str() == CHUNK_CONTENT_TYPE);
+    if !is_chunk {
+        return Err(PithosError::UnsupportedMediaType);
+    }
+
+    let _lock = UploadLock::acquire(uuid)?;
+
+    let current = current_offset(&config, uuid, query.length).await?
+        .ok_or(PithosError::NoSuchFile)?;
+
+    if offset != current {
+        return Err(PithosError::OffsetMismatch(offset, current));
+    }
+
+    if current == query.length {
+        return Ok((StatusCode::NO_CONTENT, TypedHeader(UploadOffset(current))));
+    }
+
+    let mut file = OpenOptions::new().append(true).open(staging_file(&config, uuid)).await
+        .map_err(|e| PithosError::ServerError(Box::new(e)))?;
+
+    let body_with_io_error = body.map_err(Error::other);
+    let mut body_reader = StreamReader::new(body_with_io_error);
+
+    // bytes that did arrive are kept even if the connection drops, so that the client can resume from them
+    let copy_result = tokio::io::copy_buf(&mut (&mut body_reader).take(query.length - current), &mut file).await;
+    file.sync_data().await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+    let written = copy_result.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+
+    if body_reader.read(&mut [0]).await.map_err(|e| PithosError::ServerError(Box::new(e)))? != 0 {
+        file.set_len(current).await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+        return Err(PithosError::ExceedsDeclaredSize(query.length));
+    }
+
+    let new_offset = current + written;
+    if new_offset == query.length {
+        file.sync_all().await.map_err(|e| PithosError::ServerError(Box::new(e)))?;
+        drop(file);
+        complete(state, uuid, digest.digest()).await?;
+    }
+
+    Ok((StatusCode::NO_CONTENT, TypedHeader(UploadOffset(new_offset))))
+}
+
+/// Terminates an unfinished resumable upload, as per the tus termination extension.
+#[axum::debug_handler]
+pub async fn terminate_handler(
+    State(state): State<&'static AppState>,
+    _: UnexpiredSignedUrl,
+    _: TusResumable,
+    Path(uuid): Path<Uuid>,
+) -> Result<StatusCode, PithosError> {
+    let _lock = UploadLock::acquire(uuid)?;
+
+    fs::remove_file(staging_file(&state.config(), uuid)).await
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => PithosError::NoSuchFile,
+            _ => PithosError::ServerError(Box::new(e))
+        })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
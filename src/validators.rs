@@ -0,0 +1,62 @@
+//! Computes the validators of locally stored files, with which clients can make conditional requests.
+//!
+//! Stored files are never modified once their upload completes, so their modification time and size
+//! identify their contents well enough for a strong entity tag.
+
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, IfRange, LastModified};
+use axum::http::HeaderMap;
+use axum::http::header::IF_NONE_MATCH;
+
+use crate::errors::PithosError;
+
+/// The validators of a stored file.
+pub struct Validators {
+    /// A strong entity tag for the contents of the file.
+    etag: ETag,
+    /// The time at which the file was last modified.
+    modified: SystemTime,
+}
+
+impl Validators {
+    /// Computes the validators of a stored file from its metadata.
+    pub fn of(metadata: &Metadata) -> Result<Self, PithosError> {
+        let modified = metadata.modified().map_err(|e| PithosError::ServerError(Box::new(e)))?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let etag = format!("\"{:x}.{:x}-{:x}\"", since_epoch.as_secs(), since_epoch.subsec_nanos(), metadata.len()).parse()
+            .map_err(|e| PithosError::ServerError(Box::new(e)))?;
+
+        Ok(Self { etag, modified })
+    }
+
+    /// Returns whether the client's copy of the file, as described by the request headers, is still current,
+    /// so that a `304 Not Modified` can be sent instead.
+    ///
+    /// As per RFC 9110, `If-Modified-Since` is only considered if the request has no `If-None-Match` header.
+    pub fn is_unmodified_for(&self, request_headers: &HeaderMap) -> bool {
+        // a missing `If-None-Match` header decodes as an empty list, so its presence is checked separately
+        if request_headers.contains_key(IF_NONE_MATCH) {
+            return request_headers.typed_get::<IfNoneMatch>()
+                .is_some_and(|if_none_match| !if_none_match.precondition_passes(&self.etag));
+        }
+
+        request_headers.typed_get::<IfModifiedSince>()
+            .is_some_and(|if_modified_since| !if_modified_since.is_modified(self.modified))
+    }
+
+    /// Returns whether the requested ranges should be sent, rather than the whole file,
+    /// which is the case unless the client's copy that `If-Range` refers to is outdated.
+    pub fn allows_ranges(&self, request_headers: &HeaderMap) -> bool {
+        request_headers.typed_get::<IfRange>()
+            .is_none_or(|if_range| !if_range.is_modified(Some(&self.etag), Some(&LastModified::from(self.modified))))
+    }
+
+    /// Adds the `ETag` and `Last-Modified` headers to a response.
+    pub fn insert_into(&self, headers: &mut HeaderMap) {
+        headers.typed_insert(self.etag.clone());
+        headers.typed_insert(LastModified::from(self.modified));
+    }
+}